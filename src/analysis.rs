@@ -0,0 +1,537 @@
+use r2pipe::R2Pipe;
+use regex::Regex;
+use serde::{Serialize, Serializer};
+use std::collections::{BTreeSet, HashMap};
+//use petgraph::graph::Graph;
+use petgraph::graphmap::GraphMap;
+
+use std::io::Error;
+#[cfg(feature = "ml")]
+use std::path::Path;
+
+#[cfg(feature = "ml")]
+use crate::inference::{self, InferenceBackend, Predictor};
+#[cfg(feature = "ml")]
+use crate::training::OPT_LABELS;
+
+pub(crate) const FUNS: [&str; 6] = [
+    "madvise",
+    "prctl",
+    "signal",
+    "sigaction",
+    "process_vm_writev",
+    "ptrace",
+];
+const STR: [(&str, &str); 12] = [
+    (r"signal[\s]*\([0x]*5,", "SIGNAL_SIGTRAP"),
+    (r"sigaction[\s]*\([0x]*5,", "SIGACT_SIGTRAP"),
+    (r"ptrace[\s]*\([0x]*0,", "PTRACE_TRACEME"),
+    (r"ptrace[\s]*\([0x]*1,", "PTRACE_PEEKTEXT"),
+    (r"ptrace[\s]*\([0x]*4,", "PTRACE_POKETEXT"),
+    (r"ptrace[\s]*\([0x]*2,", "PTRACE_PEEKDATA"),
+    (r"ptrace[\s]*\([0x]*5,", "PTRACE_POKEDATA"),
+    (r"ptrace[\s]*\(0x10,", "PTRACE_ATTACH"),
+    (r"ptrace[\s]*\(0x4206,", "PTRACE_SEIZE"),
+    (r"prctl[\s]*\([0x]*4,", "PR_SET_DUMPABLE"),
+    (r"prctl[\s]*\(0xf,", "PR_SET_NAME"),
+    (r"madvise[^\\n]*, 0x10\)", "MADV_DONTDUMP"),
+];
+
+pub(crate) fn hex<S: Serializer>(v: &u64, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&format!("{v:#x}"))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CallSite {
+    pub caller: String,
+    #[serde(serialize_with = "hex")]
+    pub caller_addr: u64,
+    #[serde(serialize_with = "hex")]
+    pub addr: u64,
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleMatch {
+    pub rule: &'static str,
+    pub caller: String,
+    pub evidence: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FunctionFinding {
+    pub name: String,
+    #[serde(serialize_with = "hex")]
+    pub addr: u64,
+    pub size: u64,
+    pub cc: u64,
+    pub calls: Vec<CallSite>,
+    pub matches: Vec<RuleMatch>,
+}
+
+impl FunctionFinding {
+    fn from_json(fun: &serde_json::Value) -> Self {
+        Self {
+            name: fun["name"].as_str().unwrap_or_default().to_string(),
+            // newer r2 reports "addr" instead of "offset"
+            addr: fun["offset"].as_u64().or(fun["addr"].as_u64()).unwrap_or(0),
+            size: fun["size"].as_u64().unwrap_or(0),
+            cc: fun["cc"].as_u64().unwrap_or(0),
+            calls: vec![],
+            matches: vec![],
+        }
+    }
+
+    fn lookup(name: &str, r2: &mut R2Pipe) -> Self {
+        let bind = r2
+            .cmdj(format!("afij @ {name}").as_str())
+            .expect("Couldn't fetch function info");
+        let mut finding = Self::from_json(&bind[0]);
+        finding.name = name.to_string();
+        finding
+    }
+}
+
+// Top tokens behind the model's verdict on one sampled function.
+#[derive(Debug, Clone, Serialize)]
+pub struct Explanation {
+    pub name: String,
+    #[serde(serialize_with = "hex")]
+    pub addr: u64,
+    pub label: String,
+    pub confidence: f32,
+    pub tokens: Vec<(String, f32)>,
+}
+
+// The compiler model's answer, averaged over the sampled functions.
+#[derive(Debug, Clone, Serialize)]
+pub struct CompilerGuess {
+    pub label: String,
+    pub confidence: f32,
+    pub functions: usize,
+}
+
+// What `inspect` does beyond the heuristic checks.
+#[derive(Debug, Default)]
+pub struct InspectOptions {
+    // number of top tokens explained per sampled function, 0 skips attribution
+    pub explain: usize,
+    // compiler model directory, inference::COMPILER_ARTIFACT_DIR when it has
+    // a model
+    pub compiler_model: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Sample {
+    pub name: String,
+    pub arch: String,
+    pub bits: u64,
+    #[serde(serialize_with = "hex")]
+    pub baddr: u64,
+    pub compiler: String,
+    // from the compiler model, None without one or in builds without ml
+    pub compiler_guess: Option<CompilerGuess>,
+    pub stripped: bool,
+    pub link_static: bool,
+    pub sect_header: bool,
+    pub functions: Vec<FunctionFinding>,
+    // percentage of sampled functions the model calls optimized, None in
+    // builds without the ml feature
+    pub optimized: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub explanations: Vec<Explanation>,
+    pub params: BTreeSet<&'static str>,
+    pub cff: Vec<FunctionFinding>
+}
+
+#[repr(usize)]
+#[derive(Debug, PartialEq)]
+enum JumpType {
+    Unconditional = 0,
+    Conditional = 1,
+}
+
+fn find_imports(s: &mut Sample, r2: &mut R2Pipe) {
+    let bind = r2.cmdj("isj").expect("Couldn't fetch imports");
+    let imp = bind.as_array().unwrap();
+    //let imp_funs: Vec<&str> = imp.into_iter().map(|f| f["realname"].as_str().unwrap()).collect();
+    let imp_funs: Vec<&str> = imp
+        .iter()
+        .map(|f| f["flagname"].as_str().unwrap())
+        .collect();
+
+    let matches = imp_funs
+        .iter()
+        //.filter(|&imp_fun| FUNS.iter().any(|&fun| *fun == **imp_fun))
+        .filter(|imp_fun| FUNS.iter().any(|fun| imp_fun.contains(fun)))
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+
+    s.functions = matches
+        .iter()
+        .map(|name| FunctionFinding::lookup(name, r2))
+        .collect();
+}
+
+fn find_links(s: &mut Sample, r2: &mut R2Pipe) {
+    let bind = r2.cmdj("aflj").expect("Couldn't fetch functions");
+    let link_funs: Vec<&str> = bind
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| f["name"].as_str().unwrap())
+        .collect();
+
+    let matches = link_funs
+        .iter()
+        .filter(|link_fun| FUNS.iter().any(|&fun| link_fun.ends_with(fun)))
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+
+    s.functions = matches
+        .iter()
+        .map(|name| FunctionFinding::lookup(name, r2))
+        .collect();
+}
+
+fn find_strip(s: &mut Sample, r2: &mut R2Pipe) {
+    let bind = r2.cmdj("/asj").expect("Couldn't fetch syscalls");
+    let syscalls = bind["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|sys| {
+            FUNS.iter().any(|&fun| {
+                let s = sys["name"].as_str().unwrap();
+                !s.starts_with("arch") && s.contains(fun)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut matches = vec![];
+
+    for sys in syscalls {
+        let fcn = r2
+            .cmdj(format!("afdj @ {}", sys["addr"]).as_str())
+            .expect("Coulnd't find function");
+
+        let fcn_name = fcn["name"].as_str().unwrap();
+        let sys_name = sys["name"].as_str().unwrap();
+
+        let rename = format!("{fcn_name}_{sys_name}");
+        r2.cmd(format!("afn {rename} {fcn_name}").as_str())
+            .expect("Renaming failed");
+
+        matches.push(rename.clone());
+
+        //sigaction -> signal
+        //signal shouldnt contain any syscalls
+        if sys_name.contains("sigaction") {
+            let bind = r2
+                .cmdj(format!("axtj @ {rename}").as_str())
+                .expect("Couldn't fetch sigaction callers");
+
+            let mut sig_callers = bind.as_array().unwrap().iter().collect::<Vec<_>>();
+
+            sig_callers.sort_by_key(|x| x["fcn_addr"].as_u64().unwrap());
+            sig_callers.dedup_by_key(|x| x["fcn_addr"].as_u64().unwrap());
+
+            for sig_call in sig_callers {
+                //for sig_call in bind.as_array().unwrap() {
+                let signal_fcn = sig_call["fcn_name"].as_str().unwrap();
+                let disas = r2
+                    .cmd(format!("pif @ {} ~[0]", signal_fcn).as_str())
+                    .unwrap();
+
+                if let (false, false) = (disas.contains("svc"), disas.contains("syscall")) {
+                    let rename = format!("{signal_fcn}_signal");
+                    r2.cmd(format!("afn {rename} {signal_fcn}").as_str())
+                        .expect("Signal rename failed");
+                    matches.push(rename);
+                }
+            }
+        }
+    }
+    s.functions = matches
+        .iter()
+        .map(|name| FunctionFinding::lookup(name, r2))
+        .collect();
+}
+
+fn check_funs(s: &mut Sample, r2: &mut R2Pipe) {
+    s.functions.retain(|fun| {
+        let res = r2.cmd(format!("axg @ {} ~entry0", fun.name).as_str()).unwrap();
+        !res.is_empty()
+    });
+
+    let mut reg_map = HashMap::new();
+    for (reg, tag) in STR {
+        reg_map.insert(tag, Regex::new(reg).unwrap());
+    }
+
+    for fcn in &mut s.functions {
+        let bind = r2
+            .cmdj(format!("axtj @ {}", fcn.name).as_str())
+            .expect("Calls to fun");
+        let calls = bind.as_array().unwrap();
+
+        for fcn_call in calls {
+            let Some(fcn_name) = fcn_call["fcn_name"].as_str() else {
+                continue;
+            };
+
+            let caller_addr = fcn_call["fcn_addr"].as_u64().unwrap_or(0);
+            let addr = fcn_call["from"].as_u64().unwrap_or(0);
+            fcn.calls.push(CallSite {
+                caller: fcn_name.to_string(),
+                caller_addr,
+                addr,
+                offset: addr.saturating_sub(caller_addr),
+            });
+
+            for dec in ["pdc", "pdg"] {
+                let decomp = r2
+                    .cmd(format!("{dec} @ {fcn_name}").as_str())
+                    .expect("decomp");
+                for (tag, reg) in &reg_map {
+                    let Some(m) = reg.find(&decomp) else {
+                        continue;
+                    };
+                    s.params.insert(*tag);
+
+                    if fcn.matches.iter().any(|x| x.rule == *tag && x.caller == fcn_name) {
+                        continue;
+                    }
+                    let line_start = decomp[..m.start()].rfind('\n').map_or(0, |i| i + 1);
+                    let line_end = decomp[m.end()..].find('\n').map_or(decomp.len(), |i| m.end() + i);
+                    fcn.matches.push(RuleMatch {
+                        rule: tag,
+                        caller: fcn_name.to_string(),
+                        evidence: decomp[line_start..line_end].trim().to_string(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn check_flat_cfg(fun_vec: &[&serde_json::Value], s: &mut Sample, r2: &mut R2Pipe) {
+    'x: for fun in fun_vec.iter().rev().take(25) {
+        let Some(fcn_name) = fun["name"].as_str() else {
+            continue;
+        };
+        let bind = r2
+            .cmd(format!("agfm @ {fcn_name}").as_str())
+            .expect("graph");
+        let data: Vec<&str> = bind.split("\n").collect();
+
+        let mut graph = GraphMap::<&str, JumpType, petgraph::Directed>::new();
+        data.iter().for_each(|x| match x.find("-->") {
+            None => (),
+            Some(idx) => {
+                let src = x[0..idx].trim();
+                let tmp_dst = &x[idx + 3..];
+
+                let (dst, edge) = match tmp_dst.find(":") {
+                    None => (tmp_dst.trim(), JumpType::Unconditional),
+                    Some(idx) => {
+                        let (dst, _) = tmp_dst.split_at(idx);
+                        (dst.trim(), JumpType::Conditional)
+                    }
+                };
+                graph.add_edge(src, dst, edge);
+            }
+        });
+
+        let Some(max_node) = graph
+            .nodes()
+            .max_by_key(|n| {
+                graph
+                    .edges_directed(n, petgraph::Direction::Incoming)
+                    .count()
+            }) else {
+            continue;
+        };
+
+        for (_,_,e) in graph.edges_directed(max_node, petgraph::Direction::Incoming) {
+            if *e == JumpType::Conditional {
+                continue 'x;
+            }
+        }
+
+        for dispatch in graph.nodes().take(5) {
+            if let Some(JumpType::Unconditional) = graph.edge_weight(max_node, dispatch) {
+                s.cff.push(FunctionFinding::from_json(fun));
+                break;
+            }
+        }
+    }
+}
+
+// Disassembly of the 25 most complex functions, the ones the models look at.
+#[cfg(feature = "ml")]
+fn sample_disas<'a>(fun_vec: &[&'a serde_json::Value], r2: &mut R2Pipe) -> Vec<(&'a serde_json::Value, String)> {
+    let mut out = vec![];
+    for fun in fun_vec.iter().rev().take(25) {
+        let Some(fcn_name) = fun["name"].as_str() else {
+            continue;
+        };
+        let fun_disas = r2
+            .cmd(format!("pif @ {fcn_name}").as_str())
+            .expect("function disas");
+        out.push((*fun, fun_disas));
+    }
+    out
+}
+
+#[cfg(feature = "ml")]
+fn infer_opt(sampled: &[(&serde_json::Value, String)], s: &mut Sample, options: &InspectOptions) -> Result<(), Error> {
+    let predictor = Predictor::<InferenceBackend>::load(inference::ARTIFACT_DIR, Default::default())?;
+    predictor.expect_labels(&OPT_LABELS).map_err(|e| {
+        Error::new(e.kind(), format!("{} isn't an optimization model: {e}", inference::ARTIFACT_DIR))
+    })?;
+    let mut label = vec![];
+
+    for (fun, fun_disas) in sampled {
+        if options.explain == 0 {
+            label.push(predictor.predict(fun_disas));
+            continue;
+        }
+        let attribution = predictor.attribute(fun_disas);
+        let finding = FunctionFinding::from_json(fun);
+        s.explanations.push(Explanation {
+            name: finding.name,
+            addr: finding.addr,
+            label: OPT_LABELS[attribution.label as usize].to_string(),
+            confidence: attribution.confidence,
+            tokens: attribution.top(options.explain),
+        });
+        label.push(attribution.label);
+    }
+
+    // binaries without any function to sample get no estimate
+    s.optimized = (!label.is_empty()).then(|| 100 * label.iter().sum::<u32>() / label.len() as u32);
+    Ok(())
+}
+
+// Mean label distribution over the sampled functions. Without an explicit
+// model directory a missing default model just means no guess.
+#[cfg(feature = "ml")]
+fn infer_compiler(sampled: &[(&serde_json::Value, String)], s: &mut Sample, options: &InspectOptions) -> Result<(), Error> {
+    let dir = match &options.compiler_model {
+        Some(dir) => dir.as_str(),
+        None if Path::new(inference::COMPILER_ARTIFACT_DIR).join("model.mpk").exists() => inference::COMPILER_ARTIFACT_DIR,
+        None => return Ok(()),
+    };
+    if sampled.is_empty() {
+        return Ok(());
+    }
+    let predictor = Predictor::<InferenceBackend>::load(dir, Default::default())?;
+
+    let mut mean = vec![0.0; predictor.labels().len()];
+    for (_, fun_disas) in sampled {
+        let probs = predictor.label_probabilities(fun_disas);
+        mean.iter_mut().zip(probs).for_each(|(total, p)| *total += p / sampled.len() as f32);
+    }
+    let Some(best) = (0..mean.len()).max_by(|a, b| mean[*a].total_cmp(&mean[*b])) else {
+        return Ok(());
+    };
+    s.compiler_guess = Some(CompilerGuess {
+        label: predictor.labels()[best].clone(),
+        confidence: mean[best],
+        functions: sampled.len(),
+    });
+    Ok(())
+}
+
+#[cfg(feature = "ml")]
+fn infer_models(fun_vec: &[&serde_json::Value], s: &mut Sample, r2: &mut R2Pipe, options: &InspectOptions) -> Result<(), Error> {
+    let sampled = sample_disas(fun_vec, r2);
+    infer_opt(&sampled, s, options)?;
+    infer_compiler(&sampled, s, options)
+}
+
+#[cfg(not(feature = "ml"))]
+fn infer_models(_fun_vec: &[&serde_json::Value], _s: &mut Sample, _r2: &mut R2Pipe, _options: &InspectOptions) -> Result<(), Error> {
+    Ok(())
+}
+
+// Disassembly of every function defined in `file`, imports left out, for
+// embedding into a similarity index.
+pub fn function_disas(file: &str) -> Vec<(FunctionFinding, String)> {
+    let mut r2 = R2Pipe::spawn(file, None).unwrap();
+    r2.cmd("aaa").expect("Analysis failed");
+
+    let bind = r2.cmdj("aflj").expect("Fetch function list");
+    let mut out = vec![];
+    for fun in bind.as_array().unwrap() {
+        let finding = FunctionFinding::from_json(fun);
+        if finding.name.is_empty() || finding.name.starts_with("sym.imp.") {
+            continue;
+        }
+        let disas = r2
+            .cmd(format!("pif @ {}", finding.name).as_str())
+            .expect("function disas");
+        out.push((finding, disas));
+    }
+
+    r2.close();
+    out
+}
+
+// Errors when a model the report needs can't be loaded.
+pub fn inspect(file: &str) -> Result<Sample, Error> {
+    inspect_with(file, &InspectOptions::default())
+}
+
+pub fn inspect_with(file: &str, options: &InspectOptions) -> Result<Sample, Error> {
+    let mut r2 = R2Pipe::spawn(file, None).unwrap();
+
+    assert_ne!(r2.cmd("afi entry0").unwrap(), "\n".to_string());
+    r2.cmd("aaa").expect("Analysis failed");
+
+    let data = r2.cmdj("ij").unwrap();
+    let mut sample = Sample {
+        name: data["core"]["file"].to_string(),
+        arch: data["bin"]["arch"].to_string(),
+        bits: data["bin"]["bits"].as_u64().unwrap(),
+        baddr: data["bin"]["baddr"].as_u64().unwrap_or(0),
+        compiler: data["bin"]["compiler"].to_string(),
+        compiler_guess: None,
+        stripped: data["bin"]["stripped"].as_bool().unwrap(),
+        link_static: data["bin"]["static"].as_bool().unwrap(),
+        sect_header: r2
+            .cmdj("iSj")
+            .expect("fetch sections")
+            .as_array()
+            .unwrap()
+            .len()
+            > 3,
+        functions: vec![],
+        optimized: None,
+        explanations: vec![],
+        params: BTreeSet::new(),
+        cff: vec![]
+    };
+
+    match (sample.link_static, sample.stripped) {
+        (false, _) => find_imports(&mut sample, &mut r2),
+        (true, false) => find_links(&mut sample, &mut r2),
+        (true, true) => find_strip(&mut sample, &mut r2),
+    }
+
+    let bind = r2.cmdj("aflj").expect("Fetch function list");
+    let mut fun_vec = bind
+        .as_array()
+        .unwrap()
+        .iter()
+        .collect::<Vec<_>>();
+    fun_vec.sort_by_key(|x| x["cc"].as_u64().unwrap()); //sort by cyclomatic complexity
+    
+    check_funs(&mut sample, &mut r2);
+    check_flat_cfg(&fun_vec, &mut sample, &mut r2);
+    let inferred = infer_models(&fun_vec, &mut sample, &mut r2, options);
+
+    r2.close();
+    inferred.map(|_| sample)
+}
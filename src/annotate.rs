@@ -0,0 +1,172 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use crate::analysis::{FunctionFinding, Sample};
+
+// One annotation per address, shared by every script flavour.
+struct Note {
+    addr: u64,
+    flag: String,
+    comment: String,
+}
+
+struct Rename {
+    addr: u64,
+    name: String,
+    tag: &'static str,
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '.' { c } else { '_' })
+        .collect()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(['\n', '\r'], " ")
+}
+
+fn collect(sample: &Sample) -> (Vec<Rename>, Vec<Note>) {
+    let mut renames = vec![];
+    let mut notes = vec![];
+
+    let mut push_fun = |fun: &FunctionFinding, tag| {
+        renames.push(Rename {
+            addr: fun.addr,
+            name: sanitize(&fun.name),
+            tag,
+        })
+    };
+    sample.functions.iter().for_each(|fun| push_fun(fun, "re9k_antidebug"));
+    sample.cff.iter().for_each(|fun| push_fun(fun, "re9k_cff"));
+
+    for fun in &sample.functions {
+        for call in &fun.calls {
+            let rules = fun
+                .matches
+                .iter()
+                .filter(|m| m.caller == call.caller)
+                .collect::<Vec<_>>();
+
+            let comment = match rules.is_empty() {
+                true => format!("re9k: call to {}", fun.name),
+                false => rules
+                    .iter()
+                    .map(|m| format!("re9k: {} ({})", m.rule, m.evidence))
+                    .collect::<Vec<_>>()
+                    .join("; "),
+            };
+            let label = rules.first().map_or(fun.name.as_str(), |m| m.rule);
+
+            notes.push(Note {
+                addr: call.addr,
+                flag: format!("re9k.{}.{:x}", sanitize(&label.to_lowercase()), call.addr),
+                comment,
+            });
+        }
+    }
+
+    for fun in &sample.cff {
+        notes.push(Note {
+            addr: fun.addr,
+            flag: format!("re9k.cff.{}", sanitize(&fun.name)),
+            comment: "re9k: flattened control flow dispatcher".to_string(),
+        });
+    }
+
+    (renames, notes)
+}
+
+fn r2_script(renames: &[Rename], notes: &[Note]) -> String {
+    let mut out = String::from("fs re9k\n");
+    for r in renames {
+        writeln!(out, "afn {} @ {:#x}", r.name, r.addr).unwrap();
+    }
+    for n in notes {
+        writeln!(out, "f {} @ {:#x}", n.flag, n.addr).unwrap();
+        // base64 keeps ';' and quotes in the evidence away from the r2 parser
+        writeln!(out, "CCu base64:{} @ {:#x}", base64(&n.comment), n.addr).unwrap();
+    }
+    out.push_str("fs *\n");
+    out
+}
+
+fn ghidra_script(baddr: u64, renames: &[Rename], notes: &[Note]) -> String {
+    let mut out = String::from(
+        "# re9k annotations, run from the Ghidra script manager
+from ghidra.program.model.symbol import SourceType
+
+BADDR = ",
+    );
+    writeln!(out, "{baddr:#x}").unwrap();
+    out.push_str(
+        "
+def addr(a):
+    return currentProgram.getImageBase().add(a - BADDR)
+
+def rename(a, name, tag):
+    fun = getFunctionAt(addr(a))
+    if fun is None:
+        fun = createFunction(addr(a), name)
+    if fun is None:
+        return
+    fun.setName(name, SourceType.USER_DEFINED)
+    fun.addTag(tag)
+
+def comment(a, text):
+    setPreComment(addr(a), text)
+
+",
+    );
+    for r in renames {
+        writeln!(out, "rename({:#x}, \"{}\", \"{}\")", r.addr, r.name, r.tag).unwrap();
+    }
+    for n in notes {
+        writeln!(out, "comment({:#x}, \"{}\")", n.addr, escape(&n.comment)).unwrap();
+    }
+    out
+}
+
+fn idc_script(baddr: u64, renames: &[Rename], notes: &[Note]) -> String {
+    let mut out = String::from("// re9k annotations\n#include <idc.idc>\n\nstatic main() {\n");
+    writeln!(out, "    auto delta = get_imagebase() - {baddr:#x};").unwrap();
+    for r in renames {
+        writeln!(out, "    set_name({:#x} + delta, \"{}\", SN_NOWARN);", r.addr, r.name).unwrap();
+        writeln!(out, "    set_func_cmt({:#x} + delta, \"{}\", 1);", r.addr, r.tag).unwrap();
+    }
+    for n in notes {
+        writeln!(out, "    set_cmt({:#x} + delta, \"{}\", 0);", n.addr, escape(&n.comment)).unwrap();
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn base64(text: &str) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in text.as_bytes().chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(TABLE[((n >> (18 - 6 * i)) & 63) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+pub fn export(sample: &Sample, dir: &str) -> Result<(), std::io::Error> {
+    let dir = Path::new(dir);
+    fs::create_dir_all(dir)?;
+
+    let (renames, notes) = collect(sample);
+    fs::write(dir.join("re9k.r2"), r2_script(&renames, &notes))?;
+    fs::write(dir.join("re9k_ghidra.py"), ghidra_script(sample.baddr, &renames, &notes))?;
+    fs::write(dir.join("re9k.idc"), idc_script(sample.baddr, &renames, &notes))?;
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use crate::analysis::{FunctionFinding, Sample, FUNS};
+use crate::patch::{detect_arch, Arch};
+
+const PRELUDE: &str = "// re9k bypass shim
+// gcc -shared -fPIC -o re9k_preload.so re9k_preload.c -ldl
+// LD_PRELOAD=./re9k_preload.so ./sample
+#define _GNU_SOURCE
+#include <dlfcn.h>
+#include <signal.h>
+#include <stdarg.h>
+#include <stdio.h>
+#include <sys/mman.h>
+#include <sys/prctl.h>
+#include <sys/ptrace.h>
+
+#define REAL(name) ((__typeof__(&name))dlsym(RTLD_NEXT, #name))
+";
+
+const HOOK_PTRACE: &str = "
+long ptrace(enum __ptrace_request request, ...)
+{
+    va_list ap;
+    va_start(ap, request);
+    pid_t pid = va_arg(ap, pid_t);
+    void *addr = va_arg(ap, void *);
+    void *data = va_arg(ap, void *);
+    va_end(ap);
+
+    if (request == PTRACE_TRACEME) {
+        fprintf(stderr, \"re9k: ptrace(PTRACE_TRACEME) -> 0\\n\");
+        return 0;
+    }
+    return REAL(ptrace)(request, pid, addr, data);
+}
+";
+
+const HOOK_PRCTL: &str = "
+int prctl(int option, ...)
+{
+    va_list ap;
+    va_start(ap, option);
+    unsigned long a2 = va_arg(ap, unsigned long);
+    unsigned long a3 = va_arg(ap, unsigned long);
+    unsigned long a4 = va_arg(ap, unsigned long);
+    unsigned long a5 = va_arg(ap, unsigned long);
+    va_end(ap);
+
+    if (option == PR_SET_DUMPABLE) {
+        fprintf(stderr, \"re9k: prctl(PR_SET_DUMPABLE) skipped\\n\");
+        return 0;
+    }
+    return REAL(prctl)(option, a2, a3, a4, a5);
+}
+";
+
+// The SIGTRAP handler is kept aside and called directly from raise(), so the
+// debugger never sees the trap.
+const HOOK_SIGNAL: &str = "
+static sighandler_t re9k_trap_handler;
+
+sighandler_t signal(int signum, sighandler_t handler)
+{
+    if (signum == SIGTRAP) {
+        fprintf(stderr, \"re9k: signal(SIGTRAP) kept out of the kernel\\n\");
+        sighandler_t old = re9k_trap_handler;
+        re9k_trap_handler = handler;
+        return old ? old : SIG_DFL;
+    }
+    return REAL(signal)(signum, handler);
+}
+
+int raise(int sig)
+{
+    if (sig == SIGTRAP && re9k_trap_handler) {
+        re9k_trap_handler(sig);
+        return 0;
+    }
+    return REAL(raise)(sig);
+}
+";
+
+const HOOK_SIGACTION: &str = "
+int sigaction(int signum, const struct sigaction *act, struct sigaction *oldact)
+{
+    if (signum == SIGTRAP && act) {
+        fprintf(stderr, \"re9k: sigaction(SIGTRAP) skipped\\n\");
+        return 0;
+    }
+    return REAL(sigaction)(signum, act, oldact);
+}
+";
+
+const HOOK_MADVISE: &str = "
+int madvise(void *addr, size_t length, int advice)
+{
+    if (advice == MADV_DONTDUMP) {
+        fprintf(stderr, \"re9k: madvise(MADV_DONTDUMP) skipped\\n\");
+        return 0;
+    }
+    return REAL(madvise)(addr, length, advice);
+}
+";
+
+// Which of FUNS a finding is about. Renamed wrappers from find_strip end with
+// the most specific name, e.g. `fcn.00401000_rt_sigaction_signal`.
+fn kind(fun: &FunctionFinding) -> Option<&'static str> {
+    FUNS.iter()
+        .filter_map(|k| fun.name.rfind(k).map(|idx| (idx, *k)))
+        .max()
+        .map(|(_, k)| k)
+}
+
+fn preload_shim(sample: &Sample) -> String {
+    let mut kinds = sample.functions.iter().filter_map(kind).collect::<Vec<_>>();
+    kinds.sort();
+    kinds.dedup();
+
+    let mut out = String::from(PRELUDE);
+    for k in kinds {
+        out.push_str(match k {
+            "ptrace" => HOOK_PTRACE,
+            "prctl" => HOOK_PRCTL,
+            "signal" => HOOK_SIGNAL,
+            "sigaction" => HOOK_SIGACTION,
+            "madvise" => HOOK_MADVISE,
+            _ => continue,
+        });
+    }
+    out
+}
+
+fn gdb_script(sample: &Sample) -> String {
+    let mut out = String::from("# re9k bypass, gdb -x re9k.gdb ./sample\nset confirm off\n");
+    if sample.params.iter().any(|p| p.ends_with("SIGTRAP")) {
+        out.push_str("handle SIGTRAP nostop noprint pass\n");
+    }
+    for fun in &sample.functions {
+        let Some(k) = kind(fun) else {
+            continue;
+        };
+        writeln!(out, "\nbreak *{:#x}\ncommands\n  silent", fun.addr).unwrap();
+        match k {
+            "process_vm_writev" => writeln!(out, "  printf \"re9k: {k} @ {}\\n\"", fun.name).unwrap(),
+            _ => writeln!(
+                out,
+                "  printf \"re9k: {k} @ {} -> 0\\n\"\n  return 0\n  continue",
+                fun.name
+            )
+            .unwrap(),
+        }
+        out.push_str("end\n");
+    }
+    out.push_str("\nrun\n");
+    out
+}
+
+fn r2_script(sample: &Sample, arch: Arch) -> String {
+    let ret = match arch {
+        Arch::X86_64 => "dr rax=0;dr rip=`pv8 @ rsp`;dr rsp=rsp+8",
+        Arch::Aarch64 => "dr x0=0;dr pc=lr",
+    };
+    let mut out = String::from("# re9k bypass, r2 -d -i re9k_dbg.r2 ./sample\n");
+    for fun in &sample.functions {
+        match kind(fun) {
+            None | Some("process_vm_writev") => continue,
+            Some(_) => (),
+        }
+        writeln!(out, "db {:#x}", fun.addr).unwrap();
+        writeln!(out, "dbc {:#x} \"?e re9k: {} -> 0;{ret}\"", fun.addr, fun.name).unwrap();
+    }
+    out
+}
+
+pub fn export(sample: &Sample, dir: &str) -> Result<(), std::io::Error> {
+    let dir = Path::new(dir);
+    fs::create_dir_all(dir)?;
+
+    if !sample.link_static {
+        fs::write(dir.join("re9k_preload.c"), preload_shim(sample))?;
+        return Ok(());
+    }
+
+    fs::write(dir.join("re9k.gdb"), gdb_script(sample))?;
+    if let Ok(arch) = detect_arch(sample) {
+        fs::write(dir.join("re9k_dbg.r2"), r2_script(sample, arch))?;
+    }
+    Ok(())
+}
@@ -3,56 +3,167 @@ use burn::data::dataset::{Dataset, InMemDataset};
 use burn::{prelude::*, tensor::Tensor};
 use serde::{Deserialize, Serialize};
 
-use std::collections::{HashMap};
-use std::fs::File;
-use std::io::BufReader;
-use serde_json;
+use crate::tokenizer::Tokenizer;
+use crate::training::TrainingConfig;
+use crate::vocab::{Vocab, PAD};
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
 use rand::rngs::StdRng;
 use rand::SeedableRng;
 use rand::seq::SliceRandom;
 
+// `group` is an optional third column naming the binary or source project a
+// function came from, group-aware splits keep a group on one side.
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct MyItem {
     pub opt: u8,
     pub asm: String,
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+// A row whose tokens were encoded ahead of time, see `tokenized::preprocess`.
+#[derive(Debug, Clone)]
+pub struct EncodedItem {
+    pub opt: u8,
+    pub ids: Vec<usize>,
+}
+
+#[derive(Config)]
+pub enum SplitStrategy {
+    Random,
+    Stratified,
+    Group,
+    StratifiedGroup,
+}
+
+pub fn csv_reader(path: &str) -> csv::Result<csv::Reader<std::fs::File>> {
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(b',')
+        .from_path(path)
+}
+
+pub fn load_items(path: &str) -> csv::Result<Vec<MyItem>> {
+    csv_reader(path)?.deserialize::<MyItem>().collect()
+}
+
+// Hand out whole units in order, train first, then test, validation gets the rest.
+fn cut(units: Vec<Vec<usize>>, train_pct: usize, test_pct: usize) -> [Vec<usize>; 3] {
+    let total = units.iter().map(Vec::len).sum::<usize>();
+    let train_n = total * train_pct / 100;
+    let test_n = total * test_pct / 100;
+
+    let mut splits = [vec![], vec![], vec![]];
+    for unit in units {
+        let idx = if splits[0].len() < train_n {
+            0
+        } else if splits[2].len() < test_n {
+            2
+        } else {
+            1
+        };
+        splits[idx].extend(unit);
+    }
+    splits
+}
+
+fn majority_label(unit: &[usize], keys: &[(u8, Option<String>)]) -> u8 {
+    let mut counts = BTreeMap::new();
+    unit.iter().for_each(|row| *counts.entry(keys[*row].0).or_insert(0) += 1);
+    counts.into_iter().max_by_key(|(_, n)| *n).map_or(0, |(label, _)| label)
+}
+
+// `keys` holds the label and group of every row, returns the row indices of
+// [train, validation, test].
+pub fn split_indices(keys: &[(u8, Option<String>)], strategy: &SplitStrategy, train_pct: usize, test_pct: usize, rng: &mut StdRng) -> Result<[Vec<usize>; 3], Error> {
+    if train_pct + test_pct > 100 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("a {train_pct}% training and a {test_pct}% test split add up to more than 100%"),
+        ));
+    }
+    let units = match strategy {
+        SplitStrategy::Random | SplitStrategy::Stratified => {
+            (0..keys.len()).map(|row| vec![row]).collect::<Vec<_>>()
+        }
+        SplitStrategy::Group | SplitStrategy::StratifiedGroup => {
+            let mut groups = BTreeMap::new();
+            let mut units = vec![];
+            for (row, (_, group)) in keys.iter().enumerate() {
+                match group {
+                    Some(group) => groups.entry(group).or_insert_with(Vec::new).push(row),
+                    None => units.push(vec![row]),
+                }
+            }
+            units.extend(groups.into_values());
+            units
+        }
+    };
+
+    // groups are stratified by the label most of their functions carry
+    let strata = match strategy {
+        SplitStrategy::Stratified | SplitStrategy::StratifiedGroup => {
+            let mut strata = BTreeMap::new();
+            for unit in units {
+                strata.entry(majority_label(&unit, keys)).or_insert_with(Vec::new).push(unit);
+            }
+            strata.into_values().collect()
+        }
+        _ => vec![units],
+    };
+
+    let mut splits = [vec![], vec![], vec![]];
+    for mut stratum in strata {
+        stratum.shuffle(rng);
+        for (split, part) in splits.iter_mut().zip(cut(stratum, train_pct, test_pct)) {
+            split.extend(part);
+        }
+    }
+    splits.iter_mut().for_each(|split| split.shuffle(rng));
+    Ok(splits)
+}
+
+// Returns [train, validation, test].
+pub fn split_items(items: Vec<MyItem>, strategy: &SplitStrategy, train_pct: usize, test_pct: usize, rng: &mut StdRng) -> Result<[Vec<MyItem>; 3], Error> {
+    let keys = items.iter().map(|item| (item.opt, item.group.clone())).collect::<Vec<_>>();
+    let splits = split_indices(&keys, strategy, train_pct, test_pct, rng)?;
+
+    let mut items = items.into_iter().map(Some).collect::<Vec<_>>();
+    Ok(splits.map(|split| split.into_iter().filter_map(|row| items[row].take()).collect()))
 }
 
 pub struct MyDataset {
-    vocab: HashMap<String, usize>,
+    vocab: Arc<Vocab>,
     dataset: InMemDataset<MyItem>,
 }
 
 impl MyDataset {
-    pub fn new(ds_train: &str, split: usize, seed: u64, artifact_dir: &str) -> Result<(Self, Self), std::io::Error> {
-        let mut rng = StdRng::seed_from_u64(seed);
-        let mut vocab = HashMap::new();
-
-        let mut rdr = csv::ReaderBuilder::new()
-            .has_headers(false)
-            .delimiter(b',')
-            .from_path(ds_train)
-            .expect("read from CSV");
-
-        let mut dataset: Vec<MyItem> = rdr
-            .deserialize::<MyItem>()
-            .map(|x| x.unwrap())
-            .collect();
-
-        let mut idx = 0;
-        for item in &dataset {
-            item.asm.split_ascii_whitespace()
-                .for_each(|word|{
-                    vocab
-                        .entry(word.to_string())
-                        .or_insert_with(|| {idx += 1; idx});
-                });
+    // Split and vocab follow `config`. The test split isn't used for
+    // training, it's written to `{artifact_dir}/test.csv` for the evaluate
+    // command.
+    pub fn new(ds_train: &str, config: &TrainingConfig, artifact_dir: &str, tokenizer: &Tokenizer) -> Result<(Self, Self), Error> {
+        let mut rng = StdRng::seed_from_u64(config.seed);
+
+        let [dataset, validation, test] =
+            split_items(load_items(ds_train)?, &config.split_strategy, config.split, config.test_split, &mut rng)?;
+
+        if !test.is_empty() {
+            let mut wtr = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_path(format!("{artifact_dir}/test.csv"))?;
+            for item in &test {
+                wtr.serialize(item)?;
+            }
+            wtr.flush()?;
         }
-        let file = File::create(format!("{artifact_dir}/vocab.json"))?;
-        serde_json::to_writer(file, &vocab)?;
 
-        dataset.shuffle(&mut rng);
-        let idx_split = dataset.len() * split / 100;
-        let validation = dataset.split_off(idx_split);
+        // only the training split feeds the vocab, so validation sees real unknowns
+        let vocab = Vocab::build(dataset.iter().map(|item| tokenizer.tokenize(&item.asm)), &config.vocab);
+        vocab.save(&format!("{artifact_dir}/vocab.json"))?;
+        let vocab = Arc::new(vocab);
 
         Ok((
             Self {
@@ -71,8 +182,12 @@ impl MyDataset {
         self.vocab.len()
     }
 
-    pub fn get_vocab(&self) -> &HashMap<String, usize> {
-        &self.vocab
+    pub fn get_vocab(&self) -> Arc<Vocab> {
+        self.vocab.clone()
+    }
+
+    pub fn labels(&self) -> Vec<u8> {
+        self.dataset.iter().map(|item| item.opt).collect()
     }
 }
 
@@ -86,58 +201,218 @@ impl Dataset<MyItem> for MyDataset {
     }
 }
 
+#[derive(Config)]
+pub enum Sampling {
+    Shuffle,
+    Oversample,
+}
+
+// Rows of the rarer labels are drawn again, with replacement, until every
+// label has as many rows as the most common one.
+pub struct Oversampled<D> {
+    dataset: D,
+    rows: Vec<usize>,
+}
+
+impl<D> Oversampled<D> {
+    pub fn new(dataset: D, labels: &[u8], seed: u64) -> Self {
+        let mut by_label = BTreeMap::new();
+        for (row, label) in labels.iter().enumerate() {
+            by_label.entry(*label).or_insert_with(Vec::new).push(row);
+        }
+        let target = by_label.values().map(Vec::len).max().unwrap_or(0);
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut rows = vec![];
+        for label_rows in by_label.values() {
+            rows.extend_from_slice(label_rows);
+            rows.extend((label_rows.len()..target).filter_map(|_| label_rows.choose(&mut rng).copied()));
+        }
+        Self { dataset, rows }
+    }
+}
+
+impl<I, D: Dataset<I>> Dataset<I> for Oversampled<D> {
+    fn get(&self, index: usize) -> Option<I> {
+        self.rows.get(index).and_then(|row| self.dataset.get(*row))
+    }
+
+    fn len(&self) -> usize {
+        self.rows.len()
+    }
+}
+
+#[derive(Config)]
+pub enum Truncation {
+    Head,
+    Tail,
+    HeadTail,
+    Window { stride: usize },
+}
+
+// Cut or pad a token sequence to `seq_len`. Only `Window` yields more than one
+// sequence, the caller aggregates over them.
+pub fn fit_sequence(mut tokens: Vec<usize>, seq_len: usize, truncation: &Truncation) -> Vec<Vec<usize>> {
+    if tokens.len() > seq_len {
+        tokens = match truncation {
+            Truncation::Head => tokens[..seq_len].to_vec(),
+            Truncation::Tail => tokens[tokens.len() - seq_len..].to_vec(),
+            Truncation::HeadTail => {
+                let head = seq_len / 2;
+                let tail = seq_len - head;
+                [&tokens[..head], &tokens[tokens.len() - tail..]].concat()
+            }
+            Truncation::Window { stride } => {
+                // windows never leave gaps and the last one ends at the last token
+                let last = tokens.len() - seq_len;
+                let mut starts = (0..=last).step_by((*stride).clamp(1, seq_len)).collect::<Vec<_>>();
+                if starts.last() != Some(&last) {
+                    starts.push(last);
+                }
+                return starts.into_iter().map(|start| tokens[start..start + seq_len].to_vec()).collect();
+            }
+        };
+    }
+    tokens.resize(seq_len, PAD);
+    vec![tokens]
+}
+
+// The vocab is built once and shared by every batcher and loader worker, so
+// batching is only encoding and tensor assembly.
 #[derive(Clone)]
-pub struct ItemBatcher<'a, B: Backend> {
+pub struct ItemBatcher<B: Backend> {
     device: B::Device,
-    artifact_dir: &'a str,
-    seq_len: usize
+    vocab: Arc<Vocab>,
+    seq_len: usize,
+    truncation: Truncation,
+    mask_padding: bool,
+    tokenizer: Tokenizer
 }
 
 #[derive(Clone, Debug)]
 pub struct ItemBatch<B: Backend> {
     pub x: Tensor<B, 2, Int>,
     pub y: Tensor<B, 1, Int>,
+    pub mask_pad: Option<Tensor<B, 2, Bool>>,
 }
 
-impl<B: Backend> ItemBatcher<'_, B> {
-    pub fn new(device: B::Device, artifact_dir: &'static str, seq_len: usize) -> Self {
-        Self { device, artifact_dir, seq_len}
+impl<B: Backend> ItemBatcher<B> {
+    pub fn new(device: B::Device, vocab: Arc<Vocab>, seq_len: usize, truncation: Truncation, mask_padding: bool, tokenizer: Tokenizer) -> Self {
+        Self { device, vocab, seq_len, truncation, mask_padding, tokenizer}
     }
-}
 
-impl<B: Backend> Batcher<MyItem, ItemBatch<B>> for ItemBatcher<'_, B> {
-    fn batch(&self, items: Vec<MyItem>) -> ItemBatch<B> {
-        let batch_size = items.len();
+    fn assemble(&self, rows: impl Iterator<Item = (Vec<usize>, u8)>) -> ItemBatch<B> {
         let sequence_length = self.seq_len;
 
-        let file = File::open(format!("{}/vocab.json", self.artifact_dir)).unwrap();
-        let reader = BufReader::new(file);
-        let vocab: HashMap<String, usize> = serde_json::from_reader(reader).expect("load vocab");
-
         let mut flat_input = vec![];
         let mut flat_label = vec![];
-        
-        for item in items {
-            let mut tmp: Vec<usize> = item.asm
-            .split_ascii_whitespace()
-            .map(|word| vocab.get(word).expect("index for word").to_owned())
-            .collect();
-
-            tmp.resize(sequence_length, 0);
-            flat_input.extend_from_slice(&tmp[..]);
 
-            flat_label.push(item.opt);
+        for (tmp, opt) in rows {
+            // every window of a long function is a sample with the same label
+            for chunk in fit_sequence(tmp, sequence_length, &self.truncation) {
+                flat_input.extend_from_slice(&chunk[..]);
+                flat_label.push(opt);
+            }
         }
+        let batch_size = flat_label.len();
 
         let flat_x_tensor = Tensor::<B, 1, Int>::from_ints(&flat_input[..], &self.device);
         let flat_y_tensor = Tensor::<B, 1, Int>::from_ints(&flat_label[..], &self.device);
 
 
         let new_x = flat_x_tensor.reshape([batch_size, sequence_length]);
+        let mask_pad = self.mask_padding.then(|| new_x.clone().equal_elem(PAD as i64));
 
         ItemBatch{
             x: new_x,
-            y: flat_y_tensor
+            y: flat_y_tensor,
+            mask_pad
         }
     }
 }
+
+impl<B: Backend> Batcher<MyItem, ItemBatch<B>> for ItemBatcher<B> {
+    fn batch(&self, items: Vec<MyItem>) -> ItemBatch<B> {
+        self.assemble(
+            items
+                .into_iter()
+                .map(|item| (self.vocab.encode(&self.tokenizer.tokenize(&item.asm)), item.opt)),
+        )
+    }
+}
+
+impl<B: Backend> Batcher<EncodedItem, ItemBatch<B>> for ItemBatcher<B> {
+    fn batch(&self, items: Vec<EncodedItem>) -> ItemBatch<B> {
+        self.assemble(items.into_iter().map(|item| (item.ids, item.opt)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn windows(len: usize, seq_len: usize, stride: usize) -> Vec<Vec<usize>> {
+        fit_sequence((1..=len).collect(), seq_len, &Truncation::Window { stride })
+    }
+
+    #[test]
+    fn windows_end_at_the_last_token() {
+        assert_eq!(windows(5, 2, 2), [[1, 2], [3, 4], [4, 5]]);
+        assert_eq!(windows(6, 4, 1), [[1, 2, 3, 4], [2, 3, 4, 5], [3, 4, 5, 6]]);
+    }
+
+    #[test]
+    fn long_strides_are_clamped() {
+        assert_eq!(windows(5, 2, 10), [[1, 2], [3, 4], [4, 5]]);
+        assert_eq!(windows(3, 2, 0), [[1, 2], [2, 3]]);
+    }
+
+    #[test]
+    fn splits_over_100_percent_are_rejected() {
+        let keys = vec![(0, None); 10];
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!(split_indices(&keys, &SplitStrategy::Random, 90, 20, &mut rng).is_err());
+        let [train, valid, test] = split_indices(&keys, &SplitStrategy::Random, 80, 20, &mut rng).unwrap();
+        assert_eq!([train.len(), valid.len(), test.len()], [8, 0, 2]);
+    }
+
+    #[test]
+    fn groups_never_span_splits() {
+        // 20 groups of 5 functions with mixed labels, plus ungrouped rows
+        let mut keys = (0..100).map(|row| ((row % 3 == 0) as u8, Some(format!("bin{}", row / 5)))).collect::<Vec<_>>();
+        keys.extend((0..10).map(|row| ((row % 2) as u8, None)));
+
+        for strategy in [SplitStrategy::Group, SplitStrategy::StratifiedGroup] {
+            let mut rng = StdRng::seed_from_u64(7);
+            let splits = split_indices(&keys, &strategy, 60, 20, &mut rng).unwrap();
+
+            let mut split_of = vec![None; keys.len()];
+            for (split, rows) in splits.iter().enumerate() {
+                for row in rows {
+                    assert_eq!(split_of[*row].replace(split), None, "row {row} is in two splits");
+                }
+            }
+            assert!(split_of.iter().all(Option::is_some));
+            for group in (0..20).map(|g| g * 5..g * 5 + 5) {
+                let first = split_of[group.start];
+                assert!(group.clone().all(|row| split_of[row] == first), "rows {group:?} were split up");
+            }
+        }
+    }
+
+    #[test]
+    fn stratified_splits_keep_label_shares() {
+        let keys = (0..100).map(|row| ((row % 5 == 0) as u8, None)).collect::<Vec<_>>();
+        let mut rng = StdRng::seed_from_u64(7);
+        let splits = split_indices(&keys, &SplitStrategy::Stratified, 60, 20, &mut rng).unwrap();
+
+        let positives = splits.each_ref().map(|rows| rows.iter().filter(|row| keys[**row].0 == 1).count());
+        assert_eq!(splits.each_ref().map(Vec::len), [60, 20, 20]);
+        assert_eq!(positives, [12, 4, 4]);
+    }
+
+    #[test]
+    fn short_sequences_are_padded_once() {
+        assert_eq!(windows(2, 4, 2), [[1, 2, PAD, PAD]]);
+    }
+}
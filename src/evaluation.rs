@@ -0,0 +1,328 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+#[cfg(feature = "train")]
+use std::marker::PhantomData;
+
+use burn::prelude::*;
+#[cfg(feature = "train")]
+use burn::train::metric::state::{FormatOptions, NumericMetricState};
+#[cfg(feature = "train")]
+use burn::train::metric::{Adaptor, Metric, MetricEntry, MetricMetadata, Numeric};
+#[cfg(feature = "train")]
+use burn::train::ClassificationOutput;
+use serde::Serialize;
+
+use crate::data::load_items;
+use crate::inference::Predictor;
+
+const CALIBRATION_BINS: usize = 10;
+
+#[derive(Debug, Serialize)]
+pub struct ClassMetrics {
+    pub label: usize,
+    pub name: String,
+    pub precision: f64,
+    pub recall: f64,
+    pub f1: f64,
+    pub support: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CalibrationBin {
+    pub lower: f64,
+    pub upper: f64,
+    pub count: usize,
+    pub confidence: f64,
+    pub accuracy: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub samples: usize,
+    pub accuracy: f64,
+    pub macro_f1: f64,
+    // confusion[true][predicted]
+    pub confusion: Vec<Vec<usize>>,
+    pub classes: Vec<ClassMetrics>,
+    pub roc_auc: Option<f64>,
+    pub calibration: Vec<CalibrationBin>,
+    pub expected_calibration_error: f64,
+}
+
+fn ratio(num: usize, den: usize) -> f64 {
+    if den == 0 { 0.0 } else { num as f64 / den as f64 }
+}
+
+fn f1_score(precision: f64, recall: f64) -> f64 {
+    if precision + recall > 0.0 {
+        2.0 * precision * recall / (precision + recall)
+    } else {
+        0.0
+    }
+}
+
+// Unweighted mean F1 over every label that occurs in `labels` or `preds`.
+pub fn macro_f1(labels: &[i64], preds: &[i64]) -> f64 {
+    let classes = labels.iter().chain(preds).collect::<BTreeSet<_>>();
+    let total = classes
+        .iter()
+        .map(|c| {
+            let pairs = labels.iter().zip(preds);
+            let tp = pairs.clone().filter(|(l, p)| l == c && p == c).count();
+            let predicted = preds.iter().filter(|p| p == c).count();
+            let support = labels.iter().filter(|l| l == c).count();
+            f1_score(ratio(tp, predicted), ratio(tp, support))
+        })
+        .sum::<f64>();
+    total / classes.len().max(1) as f64
+}
+
+// Macro F1 of each batch in percent. Like the other numeric metrics the epoch
+// value is the mean over batches, not the F1 of the whole epoch.
+#[cfg(feature = "train")]
+pub struct F1Metric<B: Backend> {
+    state: NumericMetricState,
+    _backend: PhantomData<B>,
+}
+
+#[cfg(feature = "train")]
+pub struct F1Input<B: Backend> {
+    outputs: Tensor<B, 2>,
+    targets: Tensor<B, 1, Int>,
+}
+
+#[cfg(feature = "train")]
+impl<B: Backend> Adaptor<F1Input<B>> for ClassificationOutput<B> {
+    fn adapt(&self) -> F1Input<B> {
+        F1Input {
+            outputs: self.output.clone(),
+            targets: self.targets.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "train")]
+impl<B: Backend> F1Metric<B> {
+    pub fn new() -> Self {
+        Self {
+            state: NumericMetricState::default(),
+            _backend: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "train")]
+impl<B: Backend> Default for F1Metric<B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "train")]
+impl<B: Backend> Metric for F1Metric<B> {
+    const NAME: &'static str = "F1";
+
+    type Input = F1Input<B>;
+
+    fn update(&mut self, input: &F1Input<B>, _metadata: &MetricMetadata) -> MetricEntry {
+        let [batch_size, _] = input.outputs.dims();
+        let preds = input.outputs.clone().argmax(1).reshape([batch_size]);
+        let preds = preds.into_data().convert::<i64>().to_vec::<i64>().unwrap();
+        let labels = input.targets.clone().into_data().convert::<i64>().to_vec::<i64>().unwrap();
+
+        self.state.update(
+            100.0 * macro_f1(&labels, &preds),
+            batch_size,
+            FormatOptions::new(Self::NAME).unit("%").precision(2),
+        )
+    }
+
+    fn clear(&mut self) {
+        self.state.reset()
+    }
+}
+
+#[cfg(feature = "train")]
+impl<B: Backend> Numeric for F1Metric<B> {
+    fn value(&self) -> f64 {
+        self.state.value()
+    }
+}
+
+// Mann-Whitney U over the positive class score, ties count half.
+fn roc_auc(labels: &[usize], scores: &[f64]) -> Option<f64> {
+    let mut ranked = labels.iter().zip(scores).collect::<Vec<_>>();
+    ranked.sort_by(|a, b| a.1.total_cmp(b.1));
+
+    let positives = labels.iter().filter(|l| **l == 1).count();
+    let negatives = labels.len() - positives;
+    if positives == 0 || negatives == 0 {
+        return None;
+    }
+
+    let mut rank_sum = 0.0;
+    let mut idx = 0;
+    while idx < ranked.len() {
+        let tie_end = ranked[idx..]
+            .iter()
+            .position(|x| x.1 != ranked[idx].1)
+            .map_or(ranked.len(), |n| idx + n);
+        let avg_rank = (idx + 1 + tie_end) as f64 / 2.0;
+        rank_sum += avg_rank * ranked[idx..tie_end].iter().filter(|x| *x.0 == 1).count() as f64;
+        idx = tie_end;
+    }
+
+    let u = rank_sum - (positives * (positives + 1)) as f64 / 2.0;
+    Some(u / (positives * negatives) as f64)
+}
+
+// `probs` are per-sample class probabilities, only the first `names.len()`
+// entries are looked at and renormalized. Every label is below `names.len()`.
+pub fn report(names: &[String], labels: &[usize], probs: &[Vec<f32>]) -> Report {
+    let num_classes = names.len();
+    let probs = probs
+        .iter()
+        .map(|p| {
+            let p = &p[..num_classes.min(p.len())];
+            let total = p.iter().sum::<f32>().max(f32::EPSILON) as f64;
+            p.iter().map(|x| *x as f64 / total).collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let preds = probs
+        .iter()
+        .map(|p| (0..p.len()).max_by(|a, b| p[*a].total_cmp(&p[*b])).unwrap_or(0))
+        .collect::<Vec<_>>();
+
+    let mut confusion = vec![vec![0; num_classes]; num_classes];
+    for (label, pred) in labels.iter().zip(&preds) {
+        confusion[*label][*pred] += 1;
+    }
+
+    let classes = (0..num_classes)
+        .map(|c| {
+            let tp = confusion[c][c];
+            let predicted = (0..num_classes).map(|r| confusion[r][c]).sum();
+            let support = confusion[c].iter().sum();
+            let precision = ratio(tp, predicted);
+            let recall = ratio(tp, support);
+            let f1 = f1_score(precision, recall);
+            ClassMetrics { label: c, name: names[c].clone(), precision, recall, f1, support }
+        })
+        .collect::<Vec<_>>();
+
+    let correct = (0..num_classes).map(|c| confusion[c][c]).sum();
+    let roc_auc = match num_classes {
+        2 => roc_auc(labels, &probs.iter().map(|p| p[1]).collect::<Vec<_>>()),
+        _ => None,
+    };
+
+    let mut calibration = (0..CALIBRATION_BINS)
+        .map(|b| CalibrationBin {
+            lower: b as f64 / CALIBRATION_BINS as f64,
+            upper: (b + 1) as f64 / CALIBRATION_BINS as f64,
+            count: 0,
+            confidence: 0.0,
+            accuracy: 0.0,
+        })
+        .collect::<Vec<_>>();
+    for ((p, pred), label) in probs.iter().zip(&preds).zip(labels) {
+        let confidence = p[*pred];
+        let bin = &mut calibration[((confidence * CALIBRATION_BINS as f64) as usize).min(CALIBRATION_BINS - 1)];
+        bin.count += 1;
+        bin.confidence += confidence;
+        bin.accuracy += if pred == label { 1.0 } else { 0.0 };
+    }
+    let mut ece = 0.0;
+    for bin in calibration.iter_mut().filter(|bin| bin.count > 0) {
+        bin.confidence /= bin.count as f64;
+        bin.accuracy /= bin.count as f64;
+        ece += bin.count as f64 / labels.len() as f64 * (bin.accuracy - bin.confidence).abs();
+    }
+
+    Report {
+        samples: labels.len(),
+        accuracy: ratio(correct, labels.len()),
+        macro_f1: classes.iter().map(|c| c.f1).sum::<f64>() / num_classes as f64,
+        confusion,
+        classes,
+        roc_auc,
+        calibration,
+        expected_calibration_error: ece,
+    }
+}
+
+// The classes are the model's labels, a row labeled with anything else is an
+// error rather than a class the model can't predict.
+pub fn evaluate<B: Backend>(predictor: &Predictor<B>, ds_eval: &str) -> Result<Report, Error> {
+    let names = predictor.labels();
+    let items = load_items(ds_eval)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("can't read {ds_eval}: {e}")))?;
+    let labels = items.iter().map(|item| item.opt as usize).collect::<Vec<_>>();
+    if let Some(label) = labels.iter().find(|l| **l >= names.len()) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{ds_eval} has label {label}, the model only knows {names:?}"),
+        ));
+    }
+    let probs = items
+        .iter()
+        .map(|item| predictor.label_probabilities(&item.asm))
+        .collect::<Vec<_>>();
+
+    Ok(report(names, &labels, &probs))
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "samples   {}", self.samples)?;
+        writeln!(f, "accuracy  {:.4}", self.accuracy)?;
+        writeln!(f, "macro F1  {:.4}", self.macro_f1)?;
+        if let Some(auc) = self.roc_auc {
+            writeln!(f, "ROC-AUC   {auc:.4}")?;
+        }
+        writeln!(f, "ECE       {:.4}", self.expected_calibration_error)?;
+
+        // label names can be long, the name column and the cells fit the longest
+        let width = self.classes.iter().map(|c| c.name.len()).max().unwrap_or(0).max(6);
+        writeln!(f, "\nconfusion (rows true, columns predicted)")?;
+        let header = self.classes.iter().map(|c| format!("{:>w$}", c.name, w = width + 2)).collect::<String>();
+        writeln!(f, "{:>width$}{header}", "")?;
+        for (c, row) in self.classes.iter().zip(&self.confusion) {
+            let cells = row.iter().map(|n| format!("{n:>w$}", w = width + 2)).collect::<String>();
+            writeln!(f, "{:>width$}{cells}", c.name)?;
+        }
+
+        writeln!(f, "\n{:>width$}{:>11}{:>11}{:>11}{:>9}", "class", "precision", "recall", "f1", "support")?;
+        for c in &self.classes {
+            writeln!(f, "{:>width$}{:>11.4}{:>11.4}{:>11.4}{:>9}", c.name, c.precision, c.recall, c.f1, c.support)?;
+        }
+
+        writeln!(f, "\n{:>11}{:>8}{:>12}{:>10}", "bin", "count", "confidence", "accuracy")?;
+        for bin in self.calibration.iter().filter(|bin| bin.count > 0) {
+            writeln!(
+                f,
+                "{:>5.1}-{:<5.1}{:>8}{:>12.4}{:>10.4}",
+                bin.lower, bin.upper, bin.count, bin.confidence, bin.accuracy
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classes_come_from_the_label_names() {
+        let names = ["gcc", "clang", "rustc"].map(str::to_string);
+        let probs = [[0.8, 0.1, 0.1], [0.2, 0.7, 0.1]].map(|p| p.to_vec());
+        let report = report(&names, &[0, 0], &probs);
+
+        assert_eq!(report.confusion, [[1, 1, 0], [0, 0, 0], [0, 0, 0]]);
+        assert_eq!(report.classes.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), names);
+        assert_eq!(report.roc_auc, None);
+        assert!(report.to_string().contains("rustc"));
+    }
+}
@@ -1,36 +1,191 @@
-use std::collections::{HashMap};
-use std::fs::File;
-use std::io::BufReader;
-use serde_json;
-
-use burn::record::CompactRecorder;
-use burn::tensor::backend::AutodiffBackend;
+use burn::tensor::backend::Backend;
+use crate::data::fit_sequence;
+use crate::manifest::Manifest;
+use crate::model::Model;
+use crate::tokenizer::Tokenizer;
 use crate::training::TrainingConfig;
+use crate::vocab::Vocab;
 use burn::config::Config;
-use burn::record::Recorder;
-use burn::module::Module;
-
-pub fn infer<B: AutodiffBackend>(devices: Vec<B::Device>, item: String) -> u32{
-    let artifact_dir = concat!(
-        env!("CARGO_MANIFEST_DIR"),
-        "/assets");
-    let file = File::open(format!("{artifact_dir}/vocab.json")).unwrap();
-    let reader = BufReader::new(file);
-    let vocab: HashMap<String, usize> = serde_json::from_reader(reader).expect("load vocab");
-
-    let config = TrainingConfig::load(format!("{artifact_dir}/config.json"))
-        .expect("load config");
-    let record = CompactRecorder::new()
-        .load(format!("{artifact_dir}/model").into(), &devices[0])
-        .expect("load model");
-    
-    let model = config.model_conf.init::<B>(&devices[0]).load_record(record);
-    let mut enc_inp = item
-        .split_ascii_whitespace()
-        .map(|word| vocab.get(word).unwrap_or(&1).to_owned())
-        .collect::<Vec<_>>();
-
-    enc_inp.resize(64, 0);
-    model.infer(enc_inp, devices[0].clone())
+use std::io::{Error, ErrorKind};
+
+// Forward passes only, ndarray keeps libtorch off hosts that just analyze.
+pub type InferenceBackend = burn::backend::NdArray;
+
+pub const ARTIFACT_DIR: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/assets");
+
+// Optional compiler fingerprinting model, same layout as ARTIFACT_DIR.
+pub const COMPILER_ARTIFACT_DIR: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/assets/compiler");
+
+// Why a function got its label: the predicted label's probability and, per
+// token of the function, how much of it is lost when that token is occluded.
+pub struct Attribution {
+    pub label: u32,
+    pub confidence: f32,
+    pub tokens: Vec<(String, f32)>,
+}
+
+impl Attribution {
+    // Weights summed per distinct token, largest first. A mnemonic that
+    // appears many times counts with all of its occurrences.
+    pub fn top(&self, count: usize) -> Vec<(String, f32)> {
+        let mut totals: Vec<(String, f32)> = vec![];
+        for (token, weight) in &self.tokens {
+            match totals.iter_mut().find(|(seen, _)| seen == token) {
+                Some((_, total)) => *total += weight,
+                None => totals.push((token.clone(), *weight)),
+            }
+        }
+        totals.sort_by(|a, b| b.1.total_cmp(&a.1));
+        totals.truncate(count);
+        totals
+    }
+}
+
+// A trained model with the vocab, tokenizer and sequence settings it was
+// trained with, loaded once from an artifact directory.
+pub struct Predictor<B: Backend> {
+    model: Model<B>,
+    vocab: Vocab,
+    tokenizer: Tokenizer,
+    config: TrainingConfig,
+    device: B::Device,
+}
+
+impl<B: Backend> Predictor<B> {
+    pub fn load(artifact_dir: &str, device: B::Device) -> Result<Self, Error> {
+        let invalid = |what: String| Error::new(ErrorKind::InvalidData, format!("{artifact_dir}: {what}"));
+        let vocab = Vocab::load(&format!("{artifact_dir}/vocab.json"))
+            .map_err(|e| invalid(format!("can't load vocab.json: {e}")))?;
+
+        let config = TrainingConfig::load(format!("{artifact_dir}/config.json"))
+            .map_err(|e| invalid(format!("can't load config.json: {e}")))?;
+        let model = config.model_conf
+            .load::<B>(&format!("{artifact_dir}/model"), &device)
+            .map_err(|e| invalid(format!("can't load the model: {e}")))?;
+
+        // assets from before manifests only get a warning, a manifest that
+        // doesn't match is fatal
+        match Manifest::load(artifact_dir) {
+            Ok(manifest) => manifest
+                .check(&config, &vocab)
+                .map_err(|e| invalid(format!("refusing model: {e}")))?,
+            Err(_) => eprintln!("warning: {artifact_dir} has no manifest.json, can't check the model matches this build"),
+        }
+
+        let tokenizer = Tokenizer::new(config.tokenizer.clone());
+
+        Ok(Self { model, vocab, tokenizer, config, device })
+    }
+
+    pub fn config(&self) -> &TrainingConfig {
+        &self.config
+    }
+
+    pub fn labels(&self) -> &[String] {
+        &self.config.labels
+    }
+
+    // Callers that interpret label ids state which label set they expect.
+    pub fn expect_labels(&self, expected: &[&str]) -> Result<(), Error> {
+        match self.config.labels.iter().map(String::as_str).eq(expected.iter().copied()) {
+            true => Ok(()),
+            false => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("model predicts {:?}, expected {:?}", self.config.labels, expected),
+            )),
+        }
+    }
+
+    fn encode(&self, item: &str) -> Vec<Vec<usize>> {
+        let enc_inp = self.vocab.encode(&self.tokenizer.tokenize(item));
+        fit_sequence(enc_inp, self.config.sequence_length, &self.config.truncation)
+    }
+
+    // The most probable configured label.
+    pub fn predict(&self, item: &str) -> u32 {
+        let probs = self.label_probabilities(item);
+        (0..probs.len()).max_by(|a, b| probs[*a].total_cmp(&probs[*b])).unwrap_or(0) as u32
+    }
+
+    pub fn probabilities(&self, item: &str) -> Vec<f32> {
+        self.model.probabilities(self.encode(item), self.config.mask_padding, self.device.clone())
+    }
+
+    // Probabilities of the configured labels only. Models from before the
+    // output layer was sized to the labels have an output per vocab entry,
+    // the extra outputs are never a valid answer.
+    pub fn label_probabilities(&self, item: &str) -> Vec<f32> {
+        let mut probs = self.probabilities(item);
+        probs.truncate(self.config.labels.len());
+        probs
+    }
+
+    pub fn embed(&self, item: &str) -> Vec<f32> {
+        self.model.embedding(self.encode(item), self.config.mask_padding, self.device.clone())
+    }
+
+    // Occlusion attribution, works on any backend since it only needs forward
+    // passes. Each token is replaced by the unknown id in turn and the
+    // occluded copies are scored `batch_size` at a time. Tokens cut by
+    // truncation score 0.
+    pub fn attribute(&self, item: &str) -> Attribution {
+        let tokens = self.tokenizer.tokenize(item);
+        let ids = self.vocab.encode(&tokens);
+        let (seq_len, truncation) = (self.config.sequence_length, &self.config.truncation);
+
+        let probs = self.label_probabilities(item);
+        let label = (0..probs.len()).max_by(|a, b| probs[*a].total_cmp(&probs[*b])).unwrap_or(0);
+        let confidence = probs[label];
+
+        let mut weights = Vec::with_capacity(ids.len());
+        let positions = (0..ids.len()).collect::<Vec<_>>();
+        for batch in positions.chunks(self.config.batch_size.max(1)) {
+            let mut rows = vec![];
+            let mut windows = vec![];
+            for pos in batch {
+                let mut occluded = ids.clone();
+                occluded[*pos] = self.vocab.unknown();
+                let chunks = fit_sequence(occluded, seq_len, truncation);
+                windows.push(chunks.len());
+                rows.extend(chunks);
+            }
+
+            let mut scored = self
+                .model
+                .row_probabilities(rows, self.config.mask_padding, self.device.clone())
+                .into_iter();
+            for count in windows {
+                let occluded = scored.by_ref().take(count).map(|p| p[label]).sum::<f32>() / count as f32;
+                weights.push(confidence - occluded);
+            }
+        }
+
+        Attribution {
+            label: label as u32,
+            confidence,
+            tokens: tokens.into_iter().zip(weights).collect(),
+        }
+    }
+}
+
+pub fn infer<B: Backend>(devices: Vec<B::Device>, item: String) -> Result<u32, Error> {
+    Ok(Predictor::<B>::load(ARTIFACT_DIR, devices[0].clone())?.predict(&item))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn shipped_model_loads() {
+        let predictor = Predictor::<InferenceBackend>::load(ARTIFACT_DIR, Default::default())
+            .expect("load the shipped model");
+        let probs = predictor.label_probabilities("push mov sub call mov leave ret");
+        assert_eq!(probs.len(), predictor.labels().len());
+        assert!(probs.iter().all(|p| p.is_finite() && *p >= 0.0));
+    }
 }
@@ -1,4 +1,32 @@
+#[cfg(feature = "r2")]
+pub mod analysis;
+#[cfg(feature = "r2")]
+pub mod annotate;
+#[cfg(feature = "r2")]
+pub mod bypass;
+#[cfg(feature = "ml")]
 pub mod data;
+#[cfg(feature = "ml")]
+pub mod evaluation;
+#[cfg(feature = "ml")]
 pub mod model;
+#[cfg(feature = "r2")]
+pub mod patch;
+#[cfg(feature = "ml")]
+pub mod schedule;
+#[cfg(feature = "ml")]
+pub mod similarity;
+#[cfg(feature = "ml")]
+pub mod tokenized;
+#[cfg(feature = "ml")]
+pub mod tokenizer;
+#[cfg(feature = "ml")]
 pub mod training;
+#[cfg(feature = "train")]
+pub mod tune;
+#[cfg(feature = "ml")]
 pub mod inference;
+#[cfg(feature = "ml")]
+pub mod manifest;
+#[cfg(feature = "ml")]
+pub mod vocab;
@@ -1,343 +1,711 @@
-extern crate petgraph;
-extern crate r2pipe;
 extern crate serde_json;
 
-use r2pipe::R2Pipe;
-use regex::Regex;
-use std::collections::{HashMap, HashSet};
-//use petgraph::graph::Graph;
-use petgraph::graphmap::GraphMap;
-use clap::Parser;
+#[cfg(feature = "ml")]
+use clap::{Args, ValueEnum};
+use clap::{Parser, Subcommand};
 
+#[cfg(feature = "tch")]
 use burn::backend::libtorch::{LibTorch, LibTorchDevice};
+#[cfg(feature = "ml")]
+use burn::backend::ndarray::{NdArray, NdArrayDevice};
+#[cfg(feature = "wgpu")]
+use burn::backend::wgpu::{Wgpu, WgpuDevice};
+#[cfg(feature = "train")]
 use burn::backend::Autodiff;
-
-use re9k::training;
-use re9k::inference;
-
-const FUNS: [&str; 6] = [
-    "madvise",
-    "prctl",
-    "signal",
-    "sigaction",
-    "process_vm_writev",
-    "ptrace",
-];
-const STR: [(&str, &str); 12] = [
-    (r"signal[\s]*\([0x]*5,", "SIGNAL_SIGTRAP"),
-    (r"sigaction[\s]*\([0x]*5,", "SIGACT_SIGTRAP"),
-    (r"ptrace[\s]*\([0x]*0,", "PTRACE_TRACEME"),
-    (r"ptrace[\s]*\([0x]*1,", "PTRACE_PEEKTEXT"),
-    (r"ptrace[\s]*\([0x]*4,", "PTRACE_POKETEXT"),
-    (r"ptrace[\s]*\([0x]*2,", "PTRACE_PEEKDATA"),
-    (r"ptrace[\s]*\([0x]*5,", "PTRACE_POKEDATA"),
-    (r"ptrace[\s]*\(0x10,", "PTRACE_ATTACH"),
-    (r"ptrace[\s]*\(0x4206,", "PTRACE_SEIZE"),
-    (r"prctl[\s]*\([0x]*4,", "PR_SET_DUMPABLE"),
-    (r"prctl[\s]*\(0xf,", "PR_SET_NAME"),
-    (r"madvise[^\\n]*, 0x10\)", "MADV_DONTDUMP"),
-];
-
-#[derive(Debug)]
-struct Sample<'a> {
-    name: String,
-    arch: String,
-    bits: u64,
-    compiler: String,
-    stripped: bool,
-    link_static: bool,
-    sect_header: bool,
-    functions: Vec<String>,
-    optimized: u32,
-    params: HashSet<&'a str>,
-    cff: Vec<String>
-}
-
-#[repr(usize)]
-#[derive(Debug, PartialEq)]
-enum JumpType {
-    Unconditional = 0,
-    Conditional = 1,
-}
+#[cfg(feature = "ml")]
+use burn::config::Config;
+#[cfg(feature = "train")]
+use burn::optim::GradientClippingConfig;
+#[cfg(feature = "ml")]
+use burn::tensor::backend::Backend;
+
+#[cfg(feature = "r2")]
+use re9k::{analysis, annotate, bypass, patch};
+#[cfg(feature = "ml")]
+use re9k::data::SplitStrategy;
+#[cfg(feature = "train")]
+use re9k::data::{Sampling, Truncation};
+#[cfg(feature = "ml")]
+use re9k::evaluation;
+#[cfg(feature = "ml")]
+use re9k::inference::{self, Predictor};
+#[cfg(feature = "train")]
+use re9k::model::Architecture;
+#[cfg(feature = "train")]
+use re9k::schedule::LrSchedule;
+#[cfg(all(feature = "r2", feature = "ml"))]
+use re9k::inference::InferenceBackend;
+#[cfg(all(feature = "r2", feature = "ml"))]
+use re9k::similarity::{Entry, Index};
+#[cfg(feature = "ml")]
+use re9k::tokenized;
+#[cfg(feature = "ml")]
+use re9k::training::{self, TrainingConfig};
+#[cfg(feature = "train")]
+use re9k::training::StopMetric;
+#[cfg(feature = "train")]
+use re9k::tune;
 
 #[derive(Parser)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[cfg(feature = "r2")]
     #[arg(short, long)]
     file: Option<String>,
-    #[arg(short, long)]
-    train: Option<String>
+    #[cfg(feature = "r2")]
+    #[arg(long, value_name = "DIR", requires = "file")]
+    export_annotations: Option<String>,
+    #[cfg(feature = "r2")]
+    #[arg(long, value_name = "OUT", requires = "file")]
+    patch: Option<String>,
+    #[cfg(feature = "r2")]
+    #[arg(long, value_name = "DIR", requires = "file")]
+    bypass: Option<String>,
+    /// Report the N tokens that drove the optimization verdict per function
+    #[cfg(feature = "r2")]
+    #[arg(long, value_name = "N", default_value_t = 0, requires = "file")]
+    explain: usize,
+    /// Compiler fingerprinting model, defaults to assets/compiler when present
+    #[cfg(feature = "r2")]
+    #[arg(long, value_name = "DIR", requires = "file")]
+    compiler_model: Option<String>,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Train a model on a CSV of `opt,asm` rows
+    #[cfg(feature = "train")]
+    Train(TrainArgs),
+    /// Score a trained model against a labeled CSV
+    #[cfg(feature = "ml")]
+    Evaluate(EvalArgs),
+    /// Split and tokenize a CSV once into a directory `train` can read
+    #[cfg(feature = "ml")]
+    Preprocess(PreprocessArgs),
+    /// Train one model per point of a parameter space and rank them
+    #[cfg(feature = "train")]
+    Tune(TuneArgs),
+    /// Add the functions of binaries to a similarity index
+    #[cfg(all(feature = "r2", feature = "ml"))]
+    Index(IndexArgs),
+    /// List the indexed functions closest to each function of a binary
+    #[cfg(all(feature = "r2", feature = "ml"))]
+    Similar(SimilarArgs),
+}
 
-fn find_imports(s: &mut Sample, r2: &mut R2Pipe) {
-    let bind = r2.cmdj("isj").expect("Couldn't fetch imports");
-    let imp = bind.as_array().unwrap();
-    //let imp_funs: Vec<&str> = imp.into_iter().map(|f| f["realname"].as_str().unwrap()).collect();
-    let imp_funs: Vec<&str> = imp
-        .into_iter()
-        .map(|f| f["flagname"].as_str().unwrap())
-        .collect();
+#[cfg(feature = "ml")]
+#[derive(Clone, Copy, ValueEnum)]
+enum BackendKind {
+    Ndarray,
+    TchCpu,
+    Wgpu,
+}
 
-    let matches = imp_funs
-        .iter()
-        //.filter(|&imp_fun| FUNS.iter().any(|&fun| *fun == **imp_fun))
-        .filter(|imp_fun| FUNS.iter().any(|fun| imp_fun.contains(fun)))
-        .map(|s| s.to_string())
-        .collect::<Vec<_>>();
+#[cfg(feature = "train")]
+#[derive(Clone, Copy, ValueEnum)]
+enum ArchKind {
+    Lstm,
+    Transformer,
+    Cnn,
+}
 
-    s.functions = matches;
+#[cfg(feature = "ml")]
+#[derive(Clone, Copy, ValueEnum)]
+enum SplitKind {
+    Random,
+    Stratified,
+    Group,
+    StratifiedGroup,
 }
 
-fn find_links(s: &mut Sample, r2: &mut R2Pipe) {
-    let bind = r2.cmdj("aflj").expect("Couldn't fetch functions");
-    let link_funs: Vec<&str> = bind
-        .as_array()
-        .unwrap()
-        .into_iter()
-        .map(|f| f["name"].as_str().unwrap())
-        .collect();
-
-    let matches = link_funs
-        .iter()
-        .filter(|link_fun| FUNS.iter().any(|&fun| link_fun.ends_with(fun)))
-        .map(|s| s.to_string())
-        .collect::<Vec<_>>();
-
-    s.functions = matches;
+#[cfg(feature = "train")]
+#[derive(Clone, Copy, ValueEnum)]
+enum ScheduleKind {
+    Constant,
+    Cosine,
+    Step,
 }
 
-fn find_strip(s: &mut Sample, r2: &mut R2Pipe) {
-    let bind = r2.cmdj("/asj").expect("Couldn't fetch syscalls");
-    let syscalls = bind["results"]
-        .as_array()
-        .unwrap()
-        .into_iter()
-        .filter(|sys| {
-            FUNS.iter().any(|&fun| {
-                let s = sys["name"].as_str().unwrap();
-                !s.starts_with("arch") && s.contains(fun)
-            })
-        })
-        .collect::<Vec<_>>();
-
-    let mut matches = vec![];
-
-    for sys in syscalls {
-        let fcn = r2
-            .cmdj(format!("afdj @ {}", sys["addr"]).as_str())
-            .expect("Coulnd't find function");
-
-        let fcn_name = fcn["name"].as_str().unwrap();
-        let sys_name = sys["name"].as_str().unwrap();
-
-        let rename = format!("{fcn_name}_{sys_name}");
-        r2.cmd(format!("afn {rename} {fcn_name}").as_str())
-            .expect("Renaming failed");
-
-        matches.push(rename.clone());
-
-        //sigaction -> signal
-        //signal shouldnt contain any syscalls
-        if sys_name.contains("sigaction") {
-            let bind = r2
-                .cmdj(format!("axtj @ {rename}").as_str())
-                .expect("Couldn't fetch sigaction callers");
-
-            let mut sig_callers = bind.as_array().unwrap().iter().collect::<Vec<_>>();
-
-            sig_callers.sort_by_key(|x| x["fcn_addr"].as_u64().unwrap());
-            sig_callers.dedup_by_key(|x| x["fcn_addr"].as_u64().unwrap());
-
-            for sig_call in sig_callers {
-                //for sig_call in bind.as_array().unwrap() {
-                let signal_fcn = sig_call["fcn_name"].as_str().unwrap();
-                let disas = r2
-                    .cmd(format!("pif @ {} ~[0]", signal_fcn).as_str())
-                    .unwrap();
-
-                match (disas.contains("svc"), disas.contains("syscall")) {
-                    (false, false) => {
-                        let rename = format!("{signal_fcn}_signal");
-                        r2.cmd(format!("afn {rename} {signal_fcn}").as_str())
-                            .expect("Signal rename failed");
-                        matches.push(rename);
-                    }
-                    _ => (),
-                }
-            }
-        }
-    }
-    s.functions = matches;
+#[cfg(feature = "train")]
+#[derive(Clone, Copy, ValueEnum)]
+enum StopKind {
+    Loss,
+    Accuracy,
+    F1,
+}
+
+#[cfg(feature = "train")]
+#[derive(Clone, Copy, ValueEnum)]
+enum TruncKind {
+    Head,
+    Tail,
+    HeadTail,
+    Window,
+}
+
+// Settings that decide how rows are split and tokenized, shared by train and
+// preprocess.
+#[cfg(feature = "ml")]
+#[derive(Args)]
+struct DataArgs {
+    #[arg(long)]
+    seed: Option<u64>,
+    #[arg(long)]
+    split: Option<usize>,
+    #[arg(long)]
+    test_split: Option<usize>,
+    #[arg(long, value_enum)]
+    split_strategy: Option<SplitKind>,
+    /// Label names, the label column holds their index
+    #[arg(long, value_delimiter = ',')]
+    labels: Option<Vec<String>>,
+
+    #[arg(long)]
+    min_freq: Option<usize>,
+    #[arg(long)]
+    max_vocab: Option<usize>,
+
+    #[arg(long)]
+    operands: Option<bool>,
+    #[arg(long)]
+    register_classes: Option<bool>,
+    #[arg(long)]
+    bucket_immediates: Option<bool>,
+    #[arg(long)]
+    abstract_memory: Option<bool>,
+    #[arg(long)]
+    resolve_calls: Option<bool>,
+}
+
+// Every setting is optional and overrides the base config, which is
+// --config, the run being resumed or training::default_config().
+#[cfg(feature = "train")]
+#[derive(Args)]
+struct TrainArgs {
+    /// CSV of `opt,asm[,group]` rows or a directory written by preprocess
+    dataset: String,
+    #[arg(long, value_name = "FILE")]
+    config: Option<String>,
+    #[arg(long, value_name = "DIR", default_value = training::DEFAULT_ARTIFACT_DIR)]
+    artifact_dir: String,
+    /// Resume from a checkpoint epoch, the latest one when no epoch is given
+    #[arg(long, value_name = "EPOCH", num_args = 0..=1, default_missing_value = "0")]
+    resume: Option<usize>,
+    #[arg(long, value_enum, default_value = "ndarray")]
+    backend: BackendKind,
+    #[command(flatten)]
+    data: DataArgs,
+
+    #[arg(long)]
+    num_epochs: Option<usize>,
+    #[arg(long)]
+    batch_size: Option<usize>,
+    #[arg(long)]
+    sequence_length: Option<usize>,
+    #[arg(long, value_enum)]
+    truncation: Option<TruncKind>,
+    #[arg(long)]
+    window_stride: Option<usize>,
+    #[arg(long)]
+    mask_padding: Option<bool>,
+    #[arg(long)]
+    num_workers: Option<usize>,
+    #[arg(long)]
+    learning_rate: Option<f64>,
+    #[arg(long)]
+    patience: Option<usize>,
+    #[arg(long, value_enum)]
+    stop_metric: Option<StopKind>,
+    #[arg(long)]
+    class_weights: Option<bool>,
+    #[arg(long)]
+    focal_gamma: Option<f64>,
+    #[arg(long)]
+    oversample: Option<bool>,
+
+    #[arg(long)]
+    beta_1: Option<f32>,
+    #[arg(long)]
+    beta_2: Option<f32>,
+    #[arg(long)]
+    epsilon: Option<f32>,
+    #[arg(long)]
+    weight_decay: Option<f32>,
+    /// Clip the gradient L2 norm, 0 turns clipping off
+    #[arg(long)]
+    grad_clip_norm: Option<f32>,
+    #[arg(long, value_enum)]
+    lr_schedule: Option<ScheduleKind>,
+    #[arg(long)]
+    warmup_steps: Option<usize>,
+    #[arg(long, default_value_t = 0.0)]
+    min_lr_factor: f64,
+    #[arg(long, default_value_t = 1)]
+    step_epochs: usize,
+    #[arg(long, default_value_t = 0.5)]
+    step_gamma: f64,
+
+    #[arg(long, value_enum)]
+    architecture: Option<ArchKind>,
+    #[arg(long)]
+    embedding_dim: Option<usize>,
+    #[arg(long)]
+    lstm_dim: Option<usize>,
+    #[arg(long)]
+    num_layers: Option<usize>,
+    #[arg(long)]
+    bidirectional: Option<bool>,
+    #[arg(long)]
+    dropout: Option<f64>,
+    #[arg(long, default_value_t = 8)]
+    n_heads: usize,
+    #[arg(long, default_value_t = 2)]
+    n_layers: usize,
+    #[arg(long, default_value_t = 512)]
+    d_ff: usize,
+    #[arg(long, default_value_t = 5)]
+    kernel_size: usize,
+}
+
+#[cfg(feature = "ml")]
+#[derive(Args)]
+struct PreprocessArgs {
+    dataset: String,
+    #[arg(value_name = "OUT_DIR")]
+    out: String,
+    #[arg(long, value_name = "FILE")]
+    config: Option<String>,
+    #[command(flatten)]
+    data: DataArgs,
+}
+
+#[cfg(feature = "train")]
+#[derive(Clone, Copy, ValueEnum)]
+enum SearchKind {
+    Grid,
+    Random,
+}
+
+#[cfg(feature = "train")]
+#[derive(Args)]
+struct TuneArgs {
+    dataset: String,
+    /// JSON object of config paths to lists of values
+    #[arg(long, value_name = "FILE")]
+    space: String,
+    #[arg(long, value_enum, default_value = "grid")]
+    search: SearchKind,
+    /// Number of random trials
+    #[arg(long, default_value_t = 10)]
+    trials: usize,
+    #[arg(long, value_name = "FILE")]
+    config: Option<String>,
+    #[arg(long, value_name = "DIR", default_value = "tune")]
+    artifact_dir: String,
+    #[arg(long, value_enum, default_value = "ndarray")]
+    backend: BackendKind,
+    #[command(flatten)]
+    data: DataArgs,
+}
+
+#[cfg(feature = "ml")]
+#[derive(Args)]
+struct EvalArgs {
+    dataset: String,
+    #[arg(long, value_name = "DIR", default_value = inference::ARTIFACT_DIR)]
+    artifact_dir: String,
+    #[arg(long, value_enum, default_value = "ndarray")]
+    backend: BackendKind,
+    /// Also write the report as JSON
+    #[arg(long, value_name = "FILE")]
+    json: Option<String>,
+}
+
+#[cfg(all(feature = "r2", feature = "ml"))]
+#[derive(Args)]
+struct IndexArgs {
+    #[arg(required = true)]
+    binaries: Vec<String>,
+    #[arg(long, value_name = "DIR", default_value = "similarity")]
+    index: String,
+    /// Model whose encoder embeds the functions
+    #[arg(long, value_name = "DIR", default_value = inference::ARTIFACT_DIR)]
+    model: String,
+}
+
+#[cfg(all(feature = "r2", feature = "ml"))]
+#[derive(Args)]
+struct SimilarArgs {
+    binary: String,
+    #[arg(long, value_name = "DIR", default_value = "similarity")]
+    index: String,
+    #[arg(long, value_name = "DIR", default_value = inference::ARTIFACT_DIR)]
+    model: String,
+    /// Only look up this function
+    #[arg(long)]
+    function: Option<String>,
+    #[arg(long, default_value_t = 5)]
+    top: usize,
 }
 
-fn check_funs(s: &mut Sample, r2: &mut R2Pipe) {
-    s.functions = s
-        .functions
-        .iter()
-        .filter(|fun| {
-            let res = r2.cmd(format!("axg @ {fun} ~entry0").as_str()).unwrap();
-            res.len() > 0
-        })
-        .map(|s| s.to_string())
-        .collect::<Vec<String>>();
-
-    let mut reg_map = HashMap::new();
-    for (reg, tag) in STR {
-        reg_map.insert(tag, Regex::new(reg).unwrap());
+#[cfg(feature = "ml")]
+fn missing_backend(kind: BackendKind) -> ! {
+    let name = kind.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default();
+    eprintln!("re9k was built without the {name} backend, rebuild with its cargo feature");
+    std::process::exit(1);
+}
+
+#[cfg(feature = "ml")]
+fn apply_data(args: &DataArgs, config: &mut TrainingConfig) {
+    if let Some(v) = args.seed { config.seed = v; }
+    if let Some(v) = args.split { config.split = v; }
+    if let Some(v) = args.test_split { config.test_split = v; }
+    if config.split + config.test_split > 100 {
+        eprintln!("--split {} and --test-split {} add up to more than 100%", config.split, config.test_split);
+        std::process::exit(1);
+    }
+    if let Some(kind) = args.split_strategy {
+        config.split_strategy = match kind {
+            SplitKind::Random => SplitStrategy::Random,
+            SplitKind::Stratified => SplitStrategy::Stratified,
+            SplitKind::Group => SplitStrategy::Group,
+            SplitKind::StratifiedGroup => SplitStrategy::StratifiedGroup,
+        };
     }
 
-    for fcn in &s.functions {
-        let bind = r2
-            .cmdj(format!("axtj @ {fcn}").as_str())
-            .expect("Calls to fun");
-        let calls = bind.as_array().unwrap();
-
-        for fcn_call in calls {
-            let Some(fcn_name) = fcn_call["fcn_name"].as_str() else {
-                continue;
-            };
-
-            for dec in ["pdc", "pdg"] {
-                let decomp = r2
-                    .cmd(format!("{dec} @ {fcn_name}").as_str())
-                    .expect("decomp");
-                for (tag, reg) in &reg_map {
-                    if reg.is_match(&decomp) {
-                        s.params.insert(tag);
-                    }
-                }
-            }
+    if let Some(v) = &args.labels { config.labels = v.clone(); }
+
+    if let Some(v) = args.min_freq { config.vocab.min_freq = v; }
+    if args.max_vocab.is_some() { config.vocab.max_size = args.max_vocab; }
+
+    if let Some(v) = args.operands { config.tokenizer.operands = v; }
+    if let Some(v) = args.register_classes { config.tokenizer.register_classes = v; }
+    if let Some(v) = args.bucket_immediates { config.tokenizer.bucket_immediates = v; }
+    if let Some(v) = args.abstract_memory { config.tokenizer.abstract_memory = v; }
+    if let Some(v) = args.resolve_calls { config.tokenizer.resolve_calls = v; }
+}
+
+#[cfg(feature = "train")]
+fn train_config(args: &TrainArgs) -> TrainingConfig {
+    let resumed = format!("{}/config.json", args.artifact_dir);
+    let mut config = match (&args.config, args.resume) {
+        (Some(path), _) => TrainingConfig::load(path).expect("load training config"),
+        (None, Some(_)) => TrainingConfig::load(&resumed).expect("load config of resumed run"),
+        (None, None) => training::default_config(),
+    };
+
+    if let Some(v) = args.num_epochs { config.num_epochs = v; }
+    if let Some(v) = args.batch_size { config.batch_size = v; }
+    if let Some(v) = args.sequence_length { config.sequence_length = v; }
+    if let Some(v) = args.mask_padding { config.mask_padding = v; }
+    if let Some(v) = args.num_workers { config.num_workers = v; }
+    if let Some(v) = args.learning_rate { config.learning_rate = v; }
+    if let Some(v) = args.patience { config.patience = v; }
+    if let Some(kind) = args.stop_metric {
+        config.stop_metric = match kind {
+            StopKind::Loss => StopMetric::Loss,
+            StopKind::Accuracy => StopMetric::Accuracy,
+            StopKind::F1 => StopMetric::F1,
+        };
+    }
+    if let Some(v) = args.class_weights { config.class_weights = v; }
+    if args.focal_gamma.is_some() { config.focal_gamma = args.focal_gamma; }
+    if let Some(v) = args.oversample {
+        config.sampling = if v { Sampling::Oversample } else { Sampling::Shuffle };
+    }
+    if let Some(kind) = args.truncation {
+        config.truncation = match kind {
+            TruncKind::Head => Truncation::Head,
+            TruncKind::Tail => Truncation::Tail,
+            TruncKind::HeadTail => Truncation::HeadTail,
+            TruncKind::Window => Truncation::Window {
+                stride: args.window_stride.unwrap_or(config.sequence_length / 2),
+            },
+        };
+    }
+    if let Truncation::Window { stride } = config.truncation {
+        if stride == 0 || stride > config.sequence_length {
+            eprintln!("--window-stride must be between 1 and the sequence length {}", config.sequence_length);
+            std::process::exit(1);
         }
     }
-}
 
-fn check_flat_cfg(fun_vec: &Vec<&serde_json::Value>, s: &mut Sample, r2: &mut R2Pipe) {
-    'x: for fun in fun_vec.into_iter().rev().take(25) {
-        let Some(fcn_name) = fun["name"].as_str() else {
-            continue;
+    if let Some(v) = args.beta_1 { config.optimizer = config.optimizer.with_beta_1(v); }
+    if let Some(v) = args.beta_2 { config.optimizer = config.optimizer.with_beta_2(v); }
+    if let Some(v) = args.epsilon { config.optimizer = config.optimizer.with_epsilon(v); }
+    if let Some(v) = args.weight_decay { config.optimizer = config.optimizer.with_weight_decay(v); }
+    if let Some(v) = args.grad_clip_norm {
+        let clipping = (v > 0.0).then_some(GradientClippingConfig::Norm(v));
+        config.optimizer = config.optimizer.with_grad_clipping(clipping);
+    }
+    if let Some(v) = args.warmup_steps { config.warmup_steps = v; }
+    if let Some(kind) = args.lr_schedule {
+        config.lr_schedule = match kind {
+            ScheduleKind::Constant => LrSchedule::Constant,
+            ScheduleKind::Cosine => LrSchedule::Cosine { min_factor: args.min_lr_factor },
+            ScheduleKind::Step => LrSchedule::Step { epochs: args.step_epochs, gamma: args.step_gamma },
         };
-        let bind = r2
-            .cmd(format!("agfm @ {fcn_name}").as_str())
-            .expect("graph");
-        let data: Vec<&str> = bind.split("\n").collect();
-
-        let mut graph = GraphMap::<&str, JumpType, petgraph::Directed>::new();
-        data.iter().for_each(|x| match x.find("-->") {
-            None => (),
-            Some(idx) => {
-                let src = x[0..idx].trim();
-                let tmp_dst = &x[idx + 3..];
-
-                let (dst, edge) = match tmp_dst.find(":") {
-                    None => (tmp_dst.trim(), JumpType::Unconditional),
-                    Some(idx) => {
-                        let (dst, _) = tmp_dst.split_at(idx);
-                        (dst.trim(), JumpType::Conditional)
-                    }
-                };
-                graph.add_edge(src, dst, edge);
-            }
-        });
+    }
 
-        let Some(max_node) = graph
-            .nodes()
-            .max_by_key(|n| {
-                graph
-                    .edges_directed(n, petgraph::Direction::Incoming)
-                    .count()
-            }) else {
-            continue;
+    apply_data(&args.data, &mut config);
+
+    let model = &mut config.model_conf;
+    if let Some(v) = args.embedding_dim { model.embedding_dim = v; }
+    if let Some(v) = args.lstm_dim { model.lstm_dim = v; }
+    if let Some(v) = args.num_layers { model.num_layers = v; }
+    if let Some(v) = args.bidirectional { model.bidirectional = v; }
+    if let Some(v) = args.dropout { model.dropout = v; }
+    if let Some(v) = args.batch_size { model.batch_size = v; }
+    if let Some(kind) = args.architecture {
+        model.architecture = match kind {
+            ArchKind::Lstm => Architecture::Lstm,
+            ArchKind::Transformer => Architecture::Transformer {
+                n_heads: args.n_heads,
+                n_layers: args.n_layers,
+                d_ff: args.d_ff,
+            },
+            ArchKind::Cnn => Architecture::Cnn { kernel_size: args.kernel_size },
         };
+    }
+    match model.architecture {
+        // Same padding only keeps the length with a centered, odd kernel
+        Architecture::Cnn { kernel_size } if kernel_size % 2 == 0 => {
+            eprintln!("--kernel-size must be odd, got {kernel_size}");
+            std::process::exit(1);
+        }
+        Architecture::Transformer { n_heads, .. } if n_heads == 0 || model.embedding_dim % n_heads != 0 => {
+            eprintln!("--n-heads {n_heads} must divide --embedding-dim {}", model.embedding_dim);
+            std::process::exit(1);
+        }
+        _ => {}
+    }
 
-        for (_,_,e) in graph.edges_directed(max_node, petgraph::Direction::Incoming) {
-            if *e == JumpType::Conditional {
-                continue 'x;
-            }
+    config
+}
+
+#[cfg(feature = "train")]
+fn train(args: TrainArgs) {
+    let config = train_config(&args);
+    let artifact_dir = args.artifact_dir.as_str();
+    let resume = match args.resume {
+        Some(0) => Some(training::latest_checkpoint(artifact_dir).expect("no checkpoint to resume from")),
+        resume => resume,
+    };
+
+    let res = match args.backend {
+        BackendKind::Ndarray => training::run::<Autodiff<NdArray>>(
+            vec![NdArrayDevice::Cpu], &args.dataset, config, artifact_dir, resume),
+        #[cfg(feature = "tch")]
+        BackendKind::TchCpu => training::run::<Autodiff<LibTorch>>(
+            vec![LibTorchDevice::Cpu], &args.dataset, config, artifact_dir, resume),
+        #[cfg(feature = "wgpu")]
+        BackendKind::Wgpu => training::run::<Autodiff<Wgpu>>(
+            vec![WgpuDevice::default()], &args.dataset, config, artifact_dir, resume),
+        #[allow(unreachable_patterns)]
+        kind => missing_backend(kind),
+    };
+    if let Err(e) = res {
+        eprintln!("training failed: {e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(feature = "ml")]
+fn preprocess(args: PreprocessArgs) {
+    let mut config = match &args.config {
+        Some(path) => TrainingConfig::load(path).expect("load training config"),
+        None => training::default_config(),
+    };
+    apply_data(&args.data, &mut config);
+
+    if let Err(e) = tokenized::preprocess(&args.dataset, &args.out, &config) {
+        eprintln!("preprocessing failed: {e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(feature = "train")]
+fn tune(args: TuneArgs) {
+    let mut config = match &args.config {
+        Some(path) => TrainingConfig::load(path).expect("load training config"),
+        None => training::default_config(),
+    };
+    apply_data(&args.data, &mut config);
+    let space = tune::load_space(&args.space).expect("load parameter space");
+    let search = match args.search {
+        SearchKind::Grid => tune::Search::Grid,
+        SearchKind::Random => tune::Search::Random { trials: args.trials },
+    };
+
+    let (dataset, dir) = (&args.dataset, &args.artifact_dir);
+    let res = match args.backend {
+        BackendKind::Ndarray => tune::run::<Autodiff<NdArray>>(
+            vec![NdArrayDevice::Cpu], dataset, config, &space, search, dir),
+        #[cfg(feature = "tch")]
+        BackendKind::TchCpu => tune::run::<Autodiff<LibTorch>>(
+            vec![LibTorchDevice::Cpu], dataset, config, &space, search, dir),
+        #[cfg(feature = "wgpu")]
+        BackendKind::Wgpu => tune::run::<Autodiff<Wgpu>>(
+            vec![WgpuDevice::default()], dataset, config, &space, search, dir),
+        #[allow(unreachable_patterns)]
+        kind => missing_backend(kind),
+    };
+    match res {
+        Ok(board) => print!("{board}"),
+        Err(e) => {
+            eprintln!("tuning failed: {e}");
+            std::process::exit(1);
         }
+    }
+}
 
-        for dispatch in graph.nodes().take(5) {
-            if let Some(JumpType::Unconditional) = graph.edge_weight(max_node, dispatch) {
-                s.cff.push(fcn_name.to_string());
-            }
+#[cfg(feature = "ml")]
+fn load_predictor<B: Backend>(artifact_dir: &str, device: B::Device) -> Predictor<B> {
+    match Predictor::<B>::load(artifact_dir, device) {
+        Ok(predictor) => predictor,
+        Err(e) => {
+            eprintln!("loading the model failed: {e}");
+            std::process::exit(1);
         }
     }
 }
 
-fn infer_opt(fun_vec: &Vec<&serde_json::Value>, s: &mut Sample, r2: &mut R2Pipe) {
-    let devices = vec![LibTorchDevice::Cpu];
-    let mut label = vec![];
+#[cfg(feature = "ml")]
+fn evaluate_with<B: Backend>(device: B::Device, args: &EvalArgs) {
+    let predictor = load_predictor::<B>(&args.artifact_dir, device);
+    let report = match evaluation::evaluate(&predictor, &args.dataset) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("evaluation failed: {e}");
+            std::process::exit(1);
+        }
+    };
+    print!("{report}");
+
+    if let Some(path) = args.json.as_deref() {
+        let file = std::fs::File::create(path).expect("create JSON report");
+        serde_json::to_writer_pretty(file, &report).expect("write JSON report");
+    }
+}
+
+#[cfg(feature = "ml")]
+fn evaluate(args: EvalArgs) {
+    match args.backend {
+        BackendKind::Ndarray => evaluate_with::<NdArray>(NdArrayDevice::Cpu, &args),
+        #[cfg(feature = "tch")]
+        BackendKind::TchCpu => evaluate_with::<LibTorch>(LibTorchDevice::Cpu, &args),
+        #[cfg(feature = "wgpu")]
+        BackendKind::Wgpu => evaluate_with::<Wgpu>(WgpuDevice::default(), &args),
+        #[allow(unreachable_patterns)]
+        kind => missing_backend(kind),
+    }
+}
 
-    for fun in fun_vec.into_iter().rev().take(25) {
-        let Some(fcn_name) = fun["name"].as_str() else {
+// Indexed under the canonical path so the same binary is recognized however
+// it was named on the command line.
+#[cfg(all(feature = "r2", feature = "ml"))]
+fn binary_key(file: &str) -> String {
+    std::fs::canonicalize(file).map_or(file.to_string(), |path| path.display().to_string())
+}
+
+#[cfg(all(feature = "r2", feature = "ml"))]
+fn index(args: IndexArgs) {
+    let predictor = load_predictor::<InferenceBackend>(&args.model, Default::default());
+    let mut index: Option<Index> = None;
+
+    for file in &args.binaries {
+        let binary = binary_key(file);
+        let embedded = analysis::function_disas(file)
+            .into_iter()
+            .map(|(finding, disas)| (finding, predictor.embed(&disas)))
+            .collect::<Vec<_>>();
+        let Some((_, first)) = embedded.first() else {
+            eprintln!("no functions found in {file}");
             continue;
         };
-        let fun_disas = r2
-            .cmd(format!("pif @ {fcn_name} ~[0]").as_str())
-            .expect("function disas")
-            .replace('\n', " ");
 
-        label.push(inference::infer::<Autodiff<LibTorch>>(devices.clone(), fun_disas));
+        // the index is opened once the embedding size is known
+        let index = index.get_or_insert_with(|| {
+            Index::open_or_new(&args.index, first.len()).expect("open similarity index")
+        });
+        index.remove_binary(&binary);
+        for (finding, vector) in &embedded {
+            let entry = Entry { binary: binary.clone(), name: finding.name.clone(), addr: finding.addr };
+            index.add(entry, vector).expect("add to similarity index");
+        }
+        eprintln!("indexed {} functions of {file}", embedded.len());
     }
 
-    s.optimized = 100 * label.iter().sum::<u32>() / label.len() as u32;
+    if let Some(index) = index {
+        index.save(&args.index).expect("save similarity index");
+        eprintln!("{} holds {} functions", args.index, index.len());
+    }
 }
 
-fn inspect(file: &str) {
-    let mut r2 = R2Pipe::spawn(file, None).unwrap();
-
-    assert_ne!(r2.cmd("afi entry0").unwrap(), "\n".to_string());
-    r2.cmd("aaa").expect("Analysis failed");
-
-    let data = r2.cmdj("ij").unwrap();
-    let mut sample = Sample {
-        name: data["core"]["file"].to_string(),
-        arch: data["bin"]["arch"].to_string(),
-        bits: data["bin"]["bits"].as_u64().unwrap(),
-        compiler: data["bin"]["compiler"].to_string(),
-        stripped: data["bin"]["stripped"].as_bool().unwrap(),
-        link_static: data["bin"]["static"].as_bool().unwrap(),
-        sect_header: r2
-            .cmdj("iSj")
-            .expect("fetch sections")
-            .as_array()
-            .unwrap()
-            .len()
-            > 3,
-        functions: vec![],
-        optimized: 0,
-        params: HashSet::new(),
-        cff: vec![]
-    };
+#[cfg(all(feature = "r2", feature = "ml"))]
+fn similar(args: SimilarArgs) {
+    let index = Index::open(&args.index).expect("open similarity index");
+    let predictor = load_predictor::<InferenceBackend>(&args.model, Default::default());
+    let binary = binary_key(&args.binary);
 
-    match (sample.link_static, sample.stripped) {
-        (false, _) => find_imports(&mut sample, &mut r2),
-        (true, false) => find_links(&mut sample, &mut r2),
-        (true, true) => find_strip(&mut sample, &mut r2),
-    }
+    for (finding, disas) in analysis::function_disas(&args.binary) {
+        if args.function.as_ref().is_some_and(|name| *name != finding.name) {
+            continue;
+        }
+        let vector = predictor.embed(&disas);
+        if vector.len() != index.dim() {
+            eprintln!("{} holds {}-d vectors, {} embeds into {}-d", args.index, index.dim(), args.model, vector.len());
+            std::process::exit(1);
+        }
 
-    let bind = r2.cmdj("aflj").expect("Fetch function list");
-    let mut fun_vec = bind
-        .as_array()
-        .unwrap()
-        .iter()
-        .collect::<Vec<_>>();
-    fun_vec.sort_by_key(|x| x["cc"].as_u64().unwrap()); //sort by cyclomatic complexity
-    
-    check_funs(&mut sample, &mut r2);
-    check_flat_cfg(&fun_vec, &mut sample, &mut r2);
-    infer_opt(&fun_vec, &mut sample, &mut r2);
-    println!("{:?}", sample);
-
-    r2.close();
+        println!("{} @ {:#x}", finding.name, finding.addr);
+        for hit in index.search(&vector, args.top, |entry| entry.binary == binary) {
+            println!("{:>8.3}  {} @ {:#x}  {}", hit.score, hit.entry.name, hit.entry.addr, hit.entry.binary);
+        }
+    }
 }
 
 fn main() {
     let cli = Cli::parse();
+    #[cfg(feature = "r2")]
     if let Some(file) = cli.file.as_deref() {
-        inspect(file);
+        let options = analysis::InspectOptions {
+            explain: cli.explain,
+            compiler_model: cli.compiler_model.clone(),
+        };
+        let sample = match analysis::inspect_with(file, &options) {
+            Ok(sample) => sample,
+            Err(e) => {
+                eprintln!("analysis failed: {e}");
+                std::process::exit(1);
+            }
+        };
+        println!("{}", serde_json::to_string_pretty(&sample).unwrap());
+        if let Some(dir) = cli.export_annotations.as_deref() {
+            annotate::export(&sample, dir).expect("export annotations");
+        }
+        if let Some(out) = cli.patch.as_deref() {
+            let log = patch::patch(&sample, file, out).expect("patch binary");
+            eprintln!("patched {} call sites into {out}", log.len());
+        }
+        if let Some(dir) = cli.bypass.as_deref() {
+            bypass::export(&sample, dir).expect("export bypass kit");
+        }
     }
-    if let Some(ds_train) = cli.train.as_deref() {
-        let devices = vec![LibTorchDevice::Cpu];
-        training::run::<Autodiff<LibTorch>>(devices, ds_train);
+    match cli.command {
+        #[cfg(feature = "train")]
+        Some(Command::Train(args)) => train(args),
+        #[cfg(feature = "ml")]
+        Some(Command::Evaluate(args)) => evaluate(args),
+        #[cfg(feature = "ml")]
+        Some(Command::Preprocess(args)) => preprocess(args),
+        #[cfg(feature = "train")]
+        Some(Command::Tune(args)) => tune(args),
+        #[cfg(all(feature = "r2", feature = "ml"))]
+        Some(Command::Index(args)) => index(args),
+        #[cfg(all(feature = "r2", feature = "ml"))]
+        Some(Command::Similar(args)) => similar(args),
+        None => (),
     }
 }
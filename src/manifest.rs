@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read};
+use std::path::Path;
+#[cfg(feature = "train")]
+use std::process::Command;
+#[cfg(feature = "train")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::tokenizer::{TokenizerConfig, TOKENIZER_VERSION};
+use crate::training::TrainingConfig;
+#[cfg(feature = "train")]
+use crate::training;
+use crate::vocab::Vocab;
+
+pub const SCHEMA: u32 = 1;
+#[cfg(feature = "train")]
+const METRICS: [&str; 3] = ["Loss", "Accuracy", "F1"];
+
+// Written as manifest.json next to model.mpk, describes what the model was
+// trained on so a loader can tell mismatched assets apart.
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub schema: u32,
+    pub labels: Vec<String>,
+    pub dataset: String,
+    // FNV-1a 64 of the CSV, or of the encoded splits of a preprocessed directory
+    pub dataset_hash: String,
+    pub tokenizer: TokenizerConfig,
+    pub tokenizer_version: u32,
+    pub vocab_version: u32,
+    pub vocab_size: usize,
+    pub best_epoch: Option<usize>,
+    // validation means of the best epoch
+    pub metrics: BTreeMap<String, f64>,
+    // seconds since the epoch
+    pub trained_at: u64,
+    pub r2_version: Option<String>,
+    pub re9k_version: String,
+}
+
+fn fnv1a(mut reader: impl Read, mut hash: u64) -> Result<u64, Error> {
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hash);
+        }
+        for byte in &buf[..n] {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+pub fn dataset_hash(ds: &str) -> Result<String, Error> {
+    let files = match Path::new(ds).is_dir() {
+        true => vec![format!("{ds}/train.tok"), format!("{ds}/valid.tok")],
+        false => vec![ds.to_string()],
+    };
+    let hash = files.iter().try_fold(0xcbf29ce484222325, |hash, path| {
+        fnv1a(BufReader::new(File::open(path)?), hash)
+    })?;
+    Ok(format!("{hash:016x}"))
+}
+
+#[cfg(feature = "train")]
+fn r2_version() -> Option<String> {
+    let out = Command::new("r2").arg("-v").output().ok()?;
+    let text = String::from_utf8(out.stdout).ok()?;
+    text.lines().next().map(|line| line.trim().to_string())
+}
+
+impl Manifest {
+    #[cfg(feature = "train")]
+    pub fn build(ds: &str, config: &TrainingConfig, vocab: &Vocab, artifact_dir: &str, stop_metric: &str) -> Result<Self, Error> {
+        let best_epoch = training::best_validation(artifact_dir, stop_metric, config.stop_metric.lowest())
+            .map(|(epoch, _)| epoch);
+        let metrics = best_epoch
+            .map(|epoch| {
+                METRICS
+                    .iter()
+                    .filter_map(|name| Some((name.to_string(), training::validation_mean(artifact_dir, name, epoch)?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Self {
+            schema: SCHEMA,
+            labels: config.labels.clone(),
+            dataset: ds.to_string(),
+            dataset_hash: dataset_hash(ds)?,
+            tokenizer: config.tokenizer.clone(),
+            tokenizer_version: TOKENIZER_VERSION,
+            vocab_version: vocab.version(),
+            vocab_size: vocab.len(),
+            best_epoch,
+            metrics,
+            trained_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            r2_version: r2_version(),
+            re9k_version: env!("CARGO_PKG_VERSION").to_string(),
+        })
+    }
+
+    pub fn load(artifact_dir: &str) -> Result<Self, Error> {
+        let reader = BufReader::new(File::open(format!("{artifact_dir}/manifest.json"))?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    pub fn save(&self, artifact_dir: &str) -> Result<(), Error> {
+        let file = File::create(format!("{artifact_dir}/manifest.json"))?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    // Errors when the model can't be fed by this build, e.g. a newer schema or
+    // a tokenizer that splits differently from the one it was trained with.
+    pub fn check(&self, config: &TrainingConfig, vocab: &Vocab) -> Result<(), Error> {
+        let mismatch = |what: String| Err(Error::new(ErrorKind::InvalidData, what));
+        if self.schema > SCHEMA {
+            return mismatch(format!("manifest schema {} is newer than supported {SCHEMA}", self.schema));
+        }
+        if self.tokenizer_version != TOKENIZER_VERSION {
+            return mismatch(format!(
+                "model was trained with tokenizer version {}, this build tokenizes as version {TOKENIZER_VERSION}",
+                self.tokenizer_version
+            ));
+        }
+        if serde_json::to_value(&self.tokenizer)? != serde_json::to_value(&config.tokenizer)? {
+            return mismatch("tokenizer settings in manifest.json and config.json differ".to_string());
+        }
+        if self.vocab_size != vocab.len() || self.vocab_version != vocab.version() {
+            return mismatch(format!(
+                "vocab.json has {} tokens (version {}), the model was trained on {} (version {})",
+                vocab.len(),
+                vocab.version(),
+                self.vocab_size,
+                self.vocab_version
+            ));
+        }
+        if self.labels != config.labels {
+            return mismatch("labels in manifest.json and config.json differ".to_string());
+        }
+        Ok(())
+    }
+}
@@ -1,28 +1,115 @@
+#[cfg(feature = "train")]
 use crate::data::ItemBatch;
+use crate::vocab::{CLS, PAD};
+#[cfg(feature = "train")]
 use burn::nn::loss::CrossEntropyLossConfig;
 use burn::nn::{
-    lstm::{Lstm, LstmConfig},
-    Embedding, EmbeddingConfig, Linear, LinearConfig,
+    conv::{Conv1d, Conv1dConfig},
+    lstm::{Lstm, LstmConfig, LstmState},
+    transformer::{TransformerEncoder, TransformerEncoderConfig, TransformerEncoderInput},
+    Dropout, DropoutConfig, Embedding, EmbeddingConfig, Linear, LinearConfig, PaddingConfig1d,
+    PositionalEncoding, PositionalEncodingConfig,
 };
 
 use burn::prelude::*;
-use burn::tensor::activation::softmax;
+use burn::module::Ignored;
+use burn::record::{CompactRecorder, Recorder, RecorderError};
+use burn::tensor::activation::{relu, softmax};
+#[cfg(feature = "train")]
+use burn::tensor::activation::log_softmax;
+#[cfg(feature = "train")]
 use burn::tensor::backend::AutodiffBackend;
-use burn::train::ClassificationOutput;
-use burn::train::TrainOutput;
-use burn::train::TrainStep;
-use burn::train::ValidStep;
+#[cfg(feature = "train")]
+use burn::train::{ClassificationOutput, TrainOutput, TrainStep, ValidStep};
+
+#[derive(Config)]
+pub enum Architecture {
+    Lstm,
+    // CLS token pooled from a small encoder over positional embeddings
+    Transformer { n_heads: usize, n_layers: usize, d_ff: usize },
+    Cnn { kernel_size: usize },
+    // the two LSTMs of the first shipped model, the second one starts from the
+    // first one's final state. Only kept to load `assets/model.mpk`.
+    Legacy,
+}
 
 #[derive(Config)]
 pub struct ModelConfig {
-    vocab_size: usize,
-    embedding_dim: usize,
-    lstm_dim: usize,
-    batch_size: usize,
+    pub vocab_size: usize,
+    pub embedding_dim: usize,
+    pub lstm_dim: usize,
+    pub batch_size: usize,
+    #[config(default = "Architecture::Lstm")]
+    pub architecture: Architecture,
+    #[config(default = 2)]
+    pub num_layers: usize,
+    #[config(default = false)]
+    pub bidirectional: bool,
+    #[config(default = 0.0)]
+    pub dropout: f64,
+    // width of the output layer, one per label. Configs from before it was
+    // recorded have one output per vocab entry.
+    pub num_classes: Option<usize>,
+}
+
+#[derive(Module, Debug)]
+pub struct LstmEncoder<B: Backend> {
+    forward_layers: Vec<Lstm<B>>,
+    // empty unless bidirectional, one per forward layer otherwise
+    backward_layers: Vec<Lstm<B>>,
+    dropout: Dropout,
+}
+
+#[derive(Module, Debug)]
+pub struct AttentionEncoder<B: Backend> {
+    pos_encoding: PositionalEncoding<B>,
+    encoder: TransformerEncoder<B>,
+}
+
+#[derive(Module, Debug)]
+pub struct CnnEncoder<B: Backend> {
+    conv: Conv1d<B>,
+}
+
+#[derive(Module, Debug)]
+pub struct LegacyEncoder<B: Backend> {
+    lstm_layer_a: Lstm<B>,
+    lstm_layer_b: Lstm<B>,
+}
+
+// one per model, the size of the legacy variant doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Module, Debug)]
+pub enum Encoder<B: Backend> {
+    Lstm(LstmEncoder<B>),
+    Transformer(AttentionEncoder<B>),
+    Cnn(CnnEncoder<B>),
+    Legacy(LegacyEncoder<B>),
+}
+
+// How the training loss treats imbalanced labels, set from TrainingConfig and
+// not part of the saved record.
+#[derive(Clone, Debug, Default)]
+pub struct LossSettings {
+    // one weight per label, indexed by label
+    pub class_weights: Option<Vec<f32>>,
+    // focal loss with this gamma instead of cross entropy
+    pub focal_gamma: Option<f64>,
 }
 
 #[derive(Module, Debug)]
 pub struct Model<B: Backend> {
+    input: Embedding<B>,
+    encoder: Encoder<B>,
+    output: Linear<B>,
+    num_classes: usize,
+    loss: Ignored<LossSettings>,
+}
+
+// The record layout models were saved with before the encoder became
+// configurable, loaded as is and then moved into a `Model`.
+#[derive(Module, Debug)]
+pub struct LegacyModel<B: Backend> {
     input: Embedding<B>,
     lstm_layer_a: Lstm<B>,
     lstm_layer_b: Lstm<B>,
@@ -30,20 +117,45 @@ pub struct Model<B: Backend> {
     vocab_size: usize,
 }
 
+impl<B: Backend> From<LegacyModel<B>> for Model<B> {
+    fn from(legacy: LegacyModel<B>) -> Self {
+        let LegacyModel { input, lstm_layer_a, lstm_layer_b, output, vocab_size } = legacy;
+        Model {
+            input,
+            encoder: Encoder::Legacy(LegacyEncoder { lstm_layer_a, lstm_layer_b }),
+            output,
+            num_classes: vocab_size,
+            loss: Ignored(LossSettings::default()),
+        }
+    }
+}
+
 impl<B: Backend> Model<B> {
+    #[cfg(feature = "train")]
     pub fn forward(&self, item: ItemBatch<B>) -> ClassificationOutput<B> {
-        let [batch_size, seq_length] = item.x.dims();
         let device = &self.input.devices()[0];
 
         let texts = item.x.to_device(device);
         let labels = item.y.to_device(device);
+        let mask_pad = item.mask_pad.map(|mask| mask.to_device(device));
 
-        let output = self.common_fwd(texts);
-        let output = output.reshape([batch_size, seq_length*self.vocab_size]);
+        // only the first position is classified, see `common_fwd`
+        let output = self.common_fwd(texts, mask_pad);
+        let [batch_size, _, _] = output.dims();
+        let output = output.slice([0..batch_size, 0..1]).reshape([batch_size, self.num_classes]);
 
-        let loss = CrossEntropyLossConfig::new()
-            .init(&output.device())
-            .forward(output.clone(), labels.clone());
+        // labels never seen in training keep weight 1
+        let weights = self.loss.class_weights.clone().map(|mut weights| {
+            weights.resize(self.num_classes, 1.0);
+            weights
+        });
+        let loss = match self.loss.focal_gamma {
+            Some(gamma) => focal_loss(output.clone(), labels.clone(), gamma, weights),
+            None => CrossEntropyLossConfig::new()
+                .with_weights(weights)
+                .init(&output.device())
+                .forward(output.clone(), labels.clone()),
+        };
 
         ClassificationOutput {
             loss,
@@ -53,35 +165,218 @@ impl<B: Backend> Model<B> {
 
     }
 
-    pub fn infer(&self, item: Vec<usize>, device: B::Device) -> u32{
-        let flat_x_tensor = Tensor::<B, 1, Int>::from_ints(&item[..], &device);
-        let batch_size = 1;
-        let sequence_length = 64;
-        let new_x = flat_x_tensor.reshape([batch_size, sequence_length]);
+    pub fn with_loss(mut self, loss: LossSettings) -> Self {
+        self.loss = Ignored(loss);
+        self
+    }
+
+    // `chunks` are the windows of one function, their probabilities are averaged.
+    pub fn probabilities(&self, chunks: Vec<Vec<usize>>, mask_padding: bool, device: B::Device) -> Vec<f32> {
+        self.class_probabilities(chunks, mask_padding, device)
+            .mean_dim(0)
+            .into_data()
+            .convert::<f32>()
+            .to_vec::<f32>()
+            .expect("probabilities as f32")
+    }
+
+    // One distribution per row, for callers that batch unrelated sequences.
+    pub fn row_probabilities(&self, rows: Vec<Vec<usize>>, mask_padding: bool, device: B::Device) -> Vec<Vec<f32>> {
+        self.class_probabilities(rows, mask_padding, device)
+            .into_data()
+            .convert::<f32>()
+            .to_vec::<f32>()
+            .expect("probabilities as f32")
+            .chunks(self.num_classes)
+            .map(<[f32]>::to_vec)
+            .collect()
+    }
+
+    // The pooled encoder state the classification head reads, averaged over
+    // the windows of one function. Functions with similar code land close to
+    // each other, which is what the similarity index relies on.
+    pub fn embedding(&self, chunks: Vec<Vec<usize>>, mask_padding: bool, device: B::Device) -> Vec<f32> {
+        let batch_size = chunks.len();
+        let (text, mask_pad) = Self::batch(chunks, mask_padding, &device);
+        let encoded = self.encode(text, mask_pad);
+        let [_, _, dim] = encoded.dims();
+
+        encoded
+            .slice([0..batch_size, 0..1])
+            .reshape([batch_size, dim])
+            .mean_dim(0)
+            .into_data()
+            .convert::<f32>()
+            .to_vec::<f32>()
+            .expect("embedding as f32")
+    }
 
-        let text = new_x.to_device(&device);
-        let output = self.common_fwd(text);
+    fn class_probabilities(&self, rows: Vec<Vec<usize>>, mask_padding: bool, device: B::Device) -> Tensor<B, 2> {
+        let batch_size = rows.len();
+        let (text, mask_pad) = Self::batch(rows, mask_padding, &device);
+        let output = self.common_fwd(text, mask_pad);
 
         let tmp = output
-            .slice([0..1, 0..1])
-            .reshape([1, self.vocab_size]);
+            .slice([0..batch_size, 0..1])
+            .reshape([batch_size, self.num_classes]);
+        softmax(tmp, 1)
+    }
+
+    fn batch(rows: Vec<Vec<usize>>, mask_padding: bool, device: &B::Device) -> (Tensor<B, 2, Int>, Option<Tensor<B, 2, Bool>>) {
+        let batch_size = rows.len();
+        let sequence_length = rows[0].len();
+        let flat_x_tensor = Tensor::<B, 1, Int>::from_ints(&rows.concat()[..], device);
+        let text = flat_x_tensor.reshape([batch_size, sequence_length]);
+
+        let mask_pad = mask_padding.then(|| text.clone().equal_elem(PAD as i64));
+        (text, mask_pad)
+    }
+
+    fn common_fwd(&self, data: Tensor<B, 2, Int>, mask_pad: Option<Tensor<B, 2, Bool>>) -> Tensor<B, 3> {
+        self.output.forward(self.encode(data, mask_pad))
+    }
+
+    // All variants return a sequence whose first position is classified: the
+    // pooled LSTM/CNN state or the transformer's CLS token.
+    fn encode(&self, data: Tensor<B, 2, Int>, mask_pad: Option<Tensor<B, 2, Bool>>) -> Tensor<B, 3> {
+        match &self.encoder {
+            Encoder::Lstm(lstm) => lstm.forward(self.input.forward(data), mask_pad),
+            Encoder::Transformer(attention) => {
+                let [batch_size, _] = data.dims();
+                let device = data.device();
+                let cls = Tensor::<B, 2, Int>::full([batch_size, 1], CLS as i64, &device);
+                let data = Tensor::cat(vec![cls.clone(), data], 1);
+                let mask_pad = mask_pad.map(|mask| Tensor::cat(vec![cls.equal_elem(PAD as i64), mask], 1));
+                attention.forward(self.input.forward(data), mask_pad)
+            }
+            Encoder::Cnn(cnn) => cnn.forward(self.input.forward(data), mask_pad),
+            // trained without masking, padding is fed through like any token
+            Encoder::Legacy(legacy) => legacy.forward(self.input.forward(data)),
+        }
+    }
+}
+
+impl<B: Backend> LstmEncoder<B> {
+    // Each layer reads the output sequence of the one below. The last layer's
+    // final hidden state (both directions when bidirectional) is handed back
+    // as a single position.
+    fn forward(&self, input_emb: Tensor<B, 3>, mask_pad: Option<Tensor<B, 2, Bool>>) -> Tensor<B, 3> {
+        let keep = mask_pad.map(|mask| mask.bool_not().float());
+        let run = |lstm: &Lstm<B>, x: Tensor<B, 3>, keep: Option<Tensor<B, 2>>| match keep {
+            None => lstm.forward(x, None),
+            Some(keep) => masked_lstm(lstm, x, keep, None),
+        };
+
+        let mut x = input_emb;
+        let mut pooled = None;
+        for (idx, lstm) in self.forward_layers.iter().enumerate() {
+            if idx > 0 {
+                x = self.dropout.forward(x);
+            }
+            let (fwd, fwd_state) = run(lstm, x.clone(), keep.clone());
+
+            (x, pooled) = match self.backward_layers.get(idx) {
+                None => (fwd, Some(fwd_state.hidden)),
+                Some(back) => {
+                    let (bwd, bwd_state) = run(back, x.flip([1]), keep.clone().map(|k| k.flip([1])));
+                    (
+                        Tensor::cat(vec![fwd, bwd.flip([1])], 2),
+                        Some(Tensor::cat(vec![fwd_state.hidden, bwd_state.hidden], 1)),
+                    )
+                }
+            };
+        }
+        pooled.expect("at least one LSTM layer").unsqueeze_dim(1)
+    }
+}
+
+impl<B: Backend> AttentionEncoder<B> {
+    fn forward(&self, input_emb: Tensor<B, 3>, mask_pad: Option<Tensor<B, 2, Bool>>) -> Tensor<B, 3> {
+        let input = TransformerEncoderInput::new(self.pos_encoding.forward(input_emb));
+        let input = match mask_pad {
+            Some(mask) => input.mask_pad(mask),
+            None => input,
+        };
+        self.encoder.forward(input)
+    }
+}
+
+impl<B: Backend> LegacyEncoder<B> {
+    fn forward(&self, input_emb: Tensor<B, 3>) -> Tensor<B, 3> {
+        let (_, state) = self.lstm_layer_a.forward(input_emb.clone(), None);
+        let (output, _) = self.lstm_layer_b.forward(input_emb, Some(state));
+        output
+    }
+}
+
+impl<B: Backend> CnnEncoder<B> {
+    // Max pooled over time, so the sequence handed back has a single position.
+    fn forward(&self, input_emb: Tensor<B, 3>, mask_pad: Option<Tensor<B, 2, Bool>>) -> Tensor<B, 3> {
+        let features = relu(self.conv.forward(input_emb.swap_dims(1, 2))).swap_dims(1, 2);
+        let features = match mask_pad {
+            Some(mask) => features - mask.float().unsqueeze_dim::<3>(2) * 1e4,
+            None => features,
+        };
+        features.max_dim(1)
+    }
+}
+
+// Step through the sequence and only let the state advance on real tokens,
+// padded steps carry the previous state and output zeros.
+fn masked_lstm<B: Backend>(
+    lstm: &Lstm<B>,
+    input: Tensor<B, 3>,
+    keep: Tensor<B, 2>,
+    state: Option<LstmState<B, 2>>,
+) -> (Tensor<B, 3>, LstmState<B, 2>) {
+    let [batch_size, seq_length, _] = input.dims();
+    let mut state = state;
+    let mut outputs = Vec::with_capacity(seq_length);
 
-        let label = softmax(tmp, 1)
-            .argmax(1)
-            .squeeze::<1>(1)
-            .into_scalar();
+    for t in 0..seq_length {
+        let x = input.clone().slice([0..batch_size, t..t + 1]);
+        // LstmState isn't Clone, the previous state is still needed below
+        let prev = state.as_ref().map(|s| LstmState::new(s.cell.clone(), s.hidden.clone()));
+        let (out, next) = lstm.forward(x, prev);
+        let keep_t = keep.clone().slice([0..batch_size, t..t + 1]);
 
-        label.elem()
+        let (cell, hidden) = match state {
+            None => (next.cell * keep_t.clone(), next.hidden * keep_t.clone()),
+            Some(prev) => {
+                let skip = keep_t.clone().neg().add_scalar(1.0);
+                (
+                    next.cell * keep_t.clone() + prev.cell * skip.clone(),
+                    next.hidden * keep_t.clone() + prev.hidden * skip,
+                )
+            }
+        };
+        state = Some(LstmState::new(cell, hidden));
+        outputs.push(out * keep_t.unsqueeze_dim(2));
     }
 
-    fn common_fwd(&self, data: Tensor<B, 2, Int>) -> Tensor<B, 3> {
-        let input_emb = self.input.forward(data);
-        let (_, out_state) = self.lstm_layer_a.forward(input_emb.clone(), None);
-        let (out_ten, _) = self.lstm_layer_b.forward(input_emb, Some(out_state));
-        self.output.forward(out_ten)
+    (Tensor::cat(outputs, 1), state.unwrap())
+}
+
+// -w_t * (1 - p_t)^gamma * log(p_t), averaged by the weights when there are any.
+#[cfg(feature = "train")]
+fn focal_loss<B: Backend>(logits: Tensor<B, 2>, targets: Tensor<B, 1, Int>, gamma: f64, weights: Option<Vec<f32>>) -> Tensor<B, 1> {
+    let [batch_size, _] = logits.dims();
+    let device = logits.device();
+    let log_p = log_softmax(logits, 1)
+        .gather(1, targets.clone().reshape([batch_size, 1]))
+        .reshape([batch_size]);
+    let loss = log_p.clone().exp().neg().add_scalar(1.0).powf_scalar(gamma).mul(log_p).neg();
+
+    match weights {
+        None => loss.mean(),
+        Some(weights) => {
+            let weights = Tensor::<B, 1>::from_floats(&weights[..], &device).select(0, targets);
+            loss.mul(weights.clone()).sum().div(weights.sum())
+        }
     }
 }
 
+#[cfg(feature = "train")]
 impl<B: AutodiffBackend> TrainStep<ItemBatch<B>, ClassificationOutput<B>> for Model<B> {
     fn step(&self, item: ItemBatch<B>) -> TrainOutput<ClassificationOutput<B>> {
         // Perform forward pass
@@ -90,6 +385,7 @@ impl<B: AutodiffBackend> TrainStep<ItemBatch<B>, ClassificationOutput<B>> for Mo
     }
 }
 
+#[cfg(feature = "train")]
 impl<B: Backend> ValidStep<ItemBatch<B>, ClassificationOutput<B>> for Model<B> {
     fn step(&self, item: ItemBatch<B>) -> ClassificationOutput<B> {
         self.forward(item)
@@ -100,17 +396,87 @@ impl ModelConfig {
     pub fn init<B: Backend>(&self, device: &B::Device) -> Model<B> {
         let input = EmbeddingConfig::new(self.vocab_size, self.embedding_dim).init(device);
 
-        let lstm_layer_a = LstmConfig::new(self.embedding_dim, self.lstm_dim, true).init(device);
-        let lstm_layer_b = LstmConfig::new(self.embedding_dim, self.lstm_dim, true).init(device);
+        let (encoder, encoder_dim) = match &self.architecture {
+            Architecture::Lstm => {
+                let directions = if self.bidirectional { 2 } else { 1 };
+                let layer = |idx: usize| {
+                    let d_input = if idx == 0 { self.embedding_dim } else { self.lstm_dim * directions };
+                    LstmConfig::new(d_input, self.lstm_dim, true).init(device)
+                };
+                let forward_layers = (0..self.num_layers.max(1)).map(layer).collect();
+                let backward_layers = match self.bidirectional {
+                    true => (0..self.num_layers.max(1)).map(layer).collect(),
+                    false => vec![],
+                };
+                let dropout = DropoutConfig::new(self.dropout).init();
+                (
+                    Encoder::Lstm(LstmEncoder { forward_layers, backward_layers, dropout }),
+                    self.lstm_dim * directions,
+                )
+            }
+            Architecture::Transformer { n_heads, n_layers, d_ff } => {
+                let pos_encoding = PositionalEncodingConfig::new(self.embedding_dim).init(device);
+                let encoder = TransformerEncoderConfig::new(self.embedding_dim, *d_ff, *n_heads, *n_layers).init(device);
+                (Encoder::Transformer(AttentionEncoder { pos_encoding, encoder }), self.embedding_dim)
+            }
+            Architecture::Cnn { kernel_size } => {
+                let conv = Conv1dConfig::new(self.embedding_dim, self.lstm_dim, *kernel_size)
+                    .with_padding(PaddingConfig1d::Same)
+                    .init(device);
+                (Encoder::Cnn(CnnEncoder { conv }), self.lstm_dim)
+            }
+            Architecture::Legacy => return self.init_legacy(device).into(),
+        };
 
-        let output = LinearConfig::new(self.lstm_dim, self.vocab_size).init(device);
+        let num_classes = self.num_classes.unwrap_or(self.vocab_size);
+        let output = LinearConfig::new(encoder_dim, num_classes).init(device);
 
         Model {
             input,
-            lstm_layer_a,
-            lstm_layer_b,
+            encoder,
             output,
+            num_classes,
+            loss: Ignored(LossSettings::default()),
+        }
+    }
+
+    pub fn init_legacy<B: Backend>(&self, device: &B::Device) -> LegacyModel<B> {
+        LegacyModel {
+            input: EmbeddingConfig::new(self.vocab_size, self.embedding_dim).init(device),
+            lstm_layer_a: LstmConfig::new(self.embedding_dim, self.lstm_dim, true).init(device),
+            lstm_layer_b: LstmConfig::new(self.embedding_dim, self.lstm_dim, true).init(device),
+            output: LinearConfig::new(self.lstm_dim, self.vocab_size).init(device),
             vocab_size: self.vocab_size,
         }
     }
+
+    // A saved model, `Legacy` ones are read with their old record layout.
+    pub fn load<B: Backend>(&self, file: &str, device: &B::Device) -> Result<Model<B>, RecorderError> {
+        let recorder = CompactRecorder::new();
+        Ok(match self.architecture {
+            Architecture::Legacy => self.init_legacy(device).load_record(recorder.load(file.into(), device)?).into(),
+            _ => self.init(device).load_record(recorder.load(file.into(), device)?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inference::InferenceBackend;
+
+    #[test]
+    fn output_layer_has_one_unit_per_label() {
+        let device = Default::default();
+        let model = ModelConfig::new(20, 8, 4, 2).with_num_classes(Some(3)).init::<InferenceBackend>(&device);
+        let probs = model.row_probabilities(vec![vec![5, 6, 7, PAD]; 2], true, device);
+        assert_eq!(probs.iter().map(Vec::len).collect::<Vec<_>>(), [3, 3]);
+    }
+
+    #[test]
+    fn configs_without_labels_keep_one_output_per_token() {
+        let device = Default::default();
+        let model = ModelConfig::new(20, 8, 4, 2).init::<InferenceBackend>(&device);
+        assert_eq!(model.probabilities(vec![vec![5, 6, 7, PAD]], false, device).len(), 20);
+    }
 }
@@ -0,0 +1,151 @@
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind};
+
+use r2pipe::R2Pipe;
+use serde::Serialize;
+
+use crate::analysis::{hex, FunctionFinding, Sample};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Action {
+    ReturnZero,
+    Nop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Arch {
+    X86_64,
+    Aarch64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PatchEntry {
+    pub function: String,
+    pub caller: String,
+    pub rule: &'static str,
+    #[serde(serialize_with = "hex")]
+    pub addr: u64,
+    pub action: Action,
+    pub original: String,
+    pub patched: String,
+}
+
+// (rule prefix, function the rule is about, what to do with the call)
+const RULES: [(&str, &str, Action); 5] = [
+    ("PTRACE_", "ptrace", Action::ReturnZero),
+    ("PR_SET_DUMPABLE", "prctl", Action::Nop),
+    ("SIGNAL_SIGTRAP", "signal", Action::Nop),
+    ("SIGACT_SIGTRAP", "sigaction", Action::Nop),
+    ("MADV_DONTDUMP", "madvise", Action::Nop),
+];
+
+fn action_for(fun: &FunctionFinding, rule: &str) -> Option<Action> {
+    RULES
+        .iter()
+        .find(|(prefix, target, _)| rule.starts_with(prefix) && fun.name.contains(target))
+        .map(|(_, _, action)| *action)
+}
+
+fn patch_bytes(arch: Arch, action: Action, size: usize) -> String {
+    match (arch, action) {
+        // xor eax, eax
+        (Arch::X86_64, Action::ReturnZero) => format!("31c0{}", "90".repeat(size.saturating_sub(2))),
+        (Arch::X86_64, Action::Nop) => "90".repeat(size),
+        // mov x0, #0
+        (Arch::Aarch64, Action::ReturnZero) => "000080d2".to_string(),
+        (Arch::Aarch64, Action::Nop) => "1f2003d5".to_string(),
+    }
+}
+
+pub(crate) fn detect_arch(sample: &Sample) -> Result<Arch, Error> {
+    match (sample.arch.trim_matches('"'), sample.bits) {
+        ("x86", 64) => Ok(Arch::X86_64),
+        ("arm", 64) => Ok(Arch::Aarch64),
+        (arch, bits) => Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("{arch}/{bits} isn't supported"),
+        )),
+    }
+}
+
+pub fn patch(sample: &Sample, file: &str, out: &str) -> Result<Vec<PatchEntry>, Error> {
+    let arch = detect_arch(sample)?;
+    fs::copy(file, out)?;
+
+    let mut r2 = R2Pipe::spawn(out, None).map_err(Error::other)?;
+    r2.cmd("oo+").map_err(Error::other)?;
+
+    let mut log = vec![];
+    for fun in &sample.functions {
+        for call in &fun.calls {
+            let Some((rule, action)) = fun
+                .matches
+                .iter()
+                .filter(|m| m.caller == call.caller)
+                .find_map(|m| action_for(fun, m.rule).map(|action| (m.rule, action)))
+            else {
+                continue;
+            };
+
+            let bind = r2
+                .cmdj(format!("aoj @ {:#x}", call.addr).as_str())
+                .map_err(Error::other)?;
+            let ins = &bind[0];
+            if !ins["type"].as_str().unwrap_or_default().contains("call") {
+                continue;
+            }
+
+            let size = ins["size"].as_u64().unwrap_or(0) as usize;
+            let patched = patch_bytes(arch, action, size);
+            r2.cmd(format!("wx {patched} @ {:#x}", call.addr).as_str())
+                .map_err(Error::other)?;
+
+            log.push(PatchEntry {
+                function: fun.name.clone(),
+                caller: call.caller.clone(),
+                rule,
+                addr: call.addr,
+                action,
+                original: ins["bytes"].as_str().unwrap_or_default().to_string(),
+                patched,
+            });
+        }
+    }
+    r2.close();
+
+    let file = File::create(format!("{out}.patchlog.json"))?;
+    serde_json::to_writer_pretty(file, &log)?;
+    Ok(log)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finding(name: &str) -> FunctionFinding {
+        FunctionFinding { name: name.to_string(), addr: 0, size: 0, cc: 0, calls: vec![], matches: vec![] }
+    }
+
+    #[test]
+    fn x86_64_bytes_fill_the_call() {
+        assert_eq!(patch_bytes(Arch::X86_64, Action::ReturnZero, 5), "31c0909090");
+        assert_eq!(patch_bytes(Arch::X86_64, Action::Nop, 5), "9090909090");
+        assert_eq!(patch_bytes(Arch::X86_64, Action::ReturnZero, 2), "31c0");
+    }
+
+    #[test]
+    fn aarch64_bytes_are_one_instruction() {
+        assert_eq!(patch_bytes(Arch::Aarch64, Action::ReturnZero, 4), "000080d2");
+        assert_eq!(patch_bytes(Arch::Aarch64, Action::Nop, 4), "1f2003d5");
+    }
+
+    #[test]
+    fn actions_follow_rule_and_function() {
+        assert_eq!(action_for(&finding("sym.imp.ptrace"), "PTRACE_TRACEME"), Some(Action::ReturnZero));
+        assert_eq!(action_for(&finding("sym.imp.prctl"), "PR_SET_DUMPABLE"), Some(Action::Nop));
+        assert_eq!(action_for(&finding("sym.imp.madvise"), "MADV_DONTDUMP"), Some(Action::Nop));
+        // a rule only patches calls to the function it is about
+        assert_eq!(action_for(&finding("sym.imp.prctl"), "PTRACE_TRACEME"), None);
+        assert_eq!(action_for(&finding("sym.imp.prctl"), "PR_SET_NAME"), None);
+    }
+}
@@ -0,0 +1,112 @@
+use std::f64::consts::PI;
+
+use burn::config::Config;
+use burn::lr_scheduler::LrScheduler;
+use burn::tensor::backend::Backend;
+use burn::LearningRate;
+
+#[derive(Config)]
+pub enum LrSchedule {
+    Constant,
+    // half cosine from the base rate down to `min_factor` of it at the last step
+    Cosine { min_factor: f64 },
+    // multiplied by `gamma` every `epochs` epochs
+    Step { epochs: usize, gamma: f64 },
+}
+
+// Linear warmup over the first `warmup` steps, then `schedule`. The step
+// count is the record so a resumed run picks up where it stopped.
+#[derive(Clone)]
+pub struct Scheduler {
+    schedule: LrSchedule,
+    base: LearningRate,
+    warmup: usize,
+    steps_per_epoch: usize,
+    total: usize,
+    step: usize,
+}
+
+impl Scheduler {
+    pub fn new(schedule: LrSchedule, base: LearningRate, warmup: usize, steps_per_epoch: usize, num_epochs: usize) -> Self {
+        Self {
+            schedule,
+            base,
+            warmup,
+            steps_per_epoch: steps_per_epoch.max(1),
+            total: steps_per_epoch * num_epochs,
+            step: 0,
+        }
+    }
+
+    fn rate(&self, step: usize) -> LearningRate {
+        if step < self.warmup {
+            return self.base * (step + 1) as f64 / self.warmup as f64;
+        }
+        match &self.schedule {
+            LrSchedule::Constant => self.base,
+            LrSchedule::Cosine { min_factor } => {
+                let span = self.total.saturating_sub(self.warmup).max(1);
+                let progress = ((step - self.warmup) as f64 / span as f64).min(1.0);
+                self.base * (min_factor + (1.0 - min_factor) * 0.5 * (1.0 + (PI * progress).cos()))
+            }
+            LrSchedule::Step { epochs, gamma } => {
+                let decays = step / (self.steps_per_epoch * epochs).max(1);
+                self.base * gamma.powi(decays as i32)
+            }
+        }
+    }
+}
+
+impl LrScheduler for Scheduler {
+    type Record<B: Backend> = usize;
+
+    fn step(&mut self) -> LearningRate {
+        let rate = self.rate(self.step);
+        self.step += 1;
+        rate
+    }
+
+    fn to_record<B: Backend>(&self) -> Self::Record<B> {
+        self.step
+    }
+
+    fn load_record<B: Backend>(mut self, record: Self::Record<B>) -> Self {
+        self.step = record;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: LearningRate, b: LearningRate) {
+        assert!((a - b).abs() < 1e-9, "{a} != {b}");
+    }
+
+    #[test]
+    fn warmup_ramps_up_to_the_base_rate() {
+        let scheduler = Scheduler::new(LrSchedule::Constant, 0.1, 4, 10, 2);
+        assert_close(scheduler.rate(0), 0.025);
+        assert_close(scheduler.rate(3), 0.1);
+        assert_close(scheduler.rate(4), 0.1);
+    }
+
+    #[test]
+    fn cosine_runs_from_the_base_rate_to_its_floor() {
+        let scheduler = Scheduler::new(LrSchedule::Cosine { min_factor: 0.1 }, 1.0, 5, 10, 2);
+        assert_close(scheduler.rate(5), 1.0);
+        assert_close(scheduler.rate(20), 0.1);
+        // past the last step the rate stays at the floor
+        assert_close(scheduler.rate(30), 0.1);
+        assert!(scheduler.rate(12) < scheduler.rate(8));
+    }
+
+    #[test]
+    fn step_decays_every_few_epochs() {
+        let scheduler = Scheduler::new(LrSchedule::Step { epochs: 2, gamma: 0.5 }, 1.0, 0, 10, 6);
+        assert_close(scheduler.rate(19), 1.0);
+        assert_close(scheduler.rate(20), 0.5);
+        assert_close(scheduler.rate(45), 0.25);
+    }
+}
@@ -0,0 +1,220 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+const MAGIC: &[u8; 8] = b"RE9KEMB\x01";
+
+// Where an indexed function came from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub binary: String,
+    pub name: String,
+    pub addr: u64,
+}
+
+pub struct Hit<'a> {
+    pub entry: &'a Entry,
+    // cosine similarity, 1 for the same direction
+    pub score: f32,
+}
+
+// Flat nearest neighbor index in a directory. `entries.json` lists the
+// functions, `vectors.bin` is MAGIC, the dimension as u32 and one unit length
+// f32 vector per entry, all little endian. Search scans every vector.
+pub struct Index {
+    dim: usize,
+    entries: Vec<Entry>,
+    vectors: Vec<f32>,
+}
+
+fn normalized(vector: &[f32]) -> Vec<f32> {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    match norm > 0.0 {
+        true => vector.iter().map(|v| v / norm).collect(),
+        false => vector.to_vec(),
+    }
+}
+
+impl Index {
+    pub fn new(dim: usize) -> Self {
+        Self { dim, entries: vec![], vectors: vec![] }
+    }
+
+    pub fn open(dir: &str) -> Result<Self, Error> {
+        let entries: Vec<Entry> = serde_json::from_reader(BufReader::new(File::open(format!("{dir}/entries.json"))?))?;
+
+        let mut data = vec![];
+        BufReader::new(File::open(format!("{dir}/vectors.bin"))?).read_to_end(&mut data)?;
+        if data.len() < MAGIC.len() + 4 || &data[..MAGIC.len()] != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{dir}/vectors.bin isn't a similarity index")));
+        }
+        let dim = u32::from_le_bytes(data[MAGIC.len()..MAGIC.len() + 4].try_into().unwrap()) as usize;
+        let vectors = data[MAGIC.len() + 4..]
+            .chunks_exact(4)
+            .map(|v| f32::from_le_bytes([v[0], v[1], v[2], v[3]]))
+            .collect::<Vec<_>>();
+        if vectors.len() != dim * entries.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{dir} has {} entries but {} vectors of {dim}", entries.len(), vectors.len() / dim.max(1)),
+            ));
+        }
+
+        Ok(Self { dim, entries, vectors })
+    }
+
+    // A new index when `dir` has none yet.
+    pub fn open_or_new(dir: &str, dim: usize) -> Result<Self, Error> {
+        if !Path::new(dir).join("vectors.bin").exists() {
+            return Ok(Self::new(dim));
+        }
+        let index = Self::open(dir)?;
+        match index.dim == dim {
+            true => Ok(index),
+            false => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{dir} holds {}-d vectors, the model embeds into {dim}-d", index.dim),
+            )),
+        }
+    }
+
+    pub fn save(&self, dir: &str) -> Result<(), Error> {
+        fs::create_dir_all(dir)?;
+        serde_json::to_writer(BufWriter::new(File::create(format!("{dir}/entries.json"))?), &self.entries)?;
+
+        let mut data = BufWriter::new(File::create(format!("{dir}/vectors.bin"))?);
+        data.write_all(MAGIC)?;
+        data.write_all(&(self.dim as u32).to_le_bytes())?;
+        for v in &self.vectors {
+            data.write_all(&v.to_le_bytes())?;
+        }
+        data.flush()
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn add(&mut self, entry: Entry, vector: &[f32]) -> Result<(), Error> {
+        if vector.len() != self.dim {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("vector of {} for a {}-d index", vector.len(), self.dim),
+            ));
+        }
+        self.entries.push(entry);
+        self.vectors.extend(normalized(vector));
+        Ok(())
+    }
+
+    // Drops every entry of `binary`, so indexing a binary again replaces it.
+    pub fn remove_binary(&mut self, binary: &str) {
+        let mut entries = vec![];
+        let mut vectors = Vec::with_capacity(self.vectors.len());
+        for (entry, vector) in self.entries.drain(..).zip(self.vectors.chunks_exact(self.dim.max(1))) {
+            if entry.binary != binary {
+                entries.push(entry);
+                vectors.extend_from_slice(vector);
+            }
+        }
+        self.entries = entries;
+        self.vectors = vectors;
+    }
+
+    // The `count` entries most similar to `vector`, entries for which `skip`
+    // holds left out.
+    pub fn search(&self, vector: &[f32], count: usize, skip: impl Fn(&Entry) -> bool) -> Vec<Hit<'_>> {
+        let query = normalized(vector);
+        let mut hits = self
+            .entries
+            .iter()
+            .zip(self.vectors.chunks_exact(self.dim.max(1)))
+            .filter(|(entry, _)| !skip(entry))
+            .map(|(entry, v)| Hit { entry, score: v.iter().zip(&query).map(|(a, b)| a * b).sum() })
+            .collect::<Vec<_>>();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hits.truncate(count);
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(binary: &str, name: &str) -> Entry {
+        Entry { binary: binary.to_string(), name: name.to_string(), addr: 0x1000 }
+    }
+
+    fn scratch(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("re9k-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.display().to_string()
+    }
+
+    #[test]
+    fn saved_index_opens_the_same() {
+        let dir = scratch("roundtrip");
+        let mut index = Index::new(3);
+        index.add(entry("a", "main"), &[3.0, 0.0, 4.0]).unwrap();
+        index.add(entry("b", "parse"), &[0.0, 2.0, 0.0]).unwrap();
+        index.save(&dir).unwrap();
+
+        let opened = Index::open(&dir).unwrap();
+        assert_eq!((opened.dim(), opened.len()), (3, 2));
+        assert_eq!(opened.entries[1].name, "parse");
+        assert_eq!(opened.vectors, [0.6, 0.0, 0.8, 0.0, 1.0, 0.0]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn other_dimensions_are_rejected() {
+        let dir = scratch("dims");
+        let mut index = Index::new(2);
+        assert!(index.add(entry("a", "main"), &[1.0, 0.0, 0.0]).is_err());
+        assert!(index.is_empty());
+
+        index.add(entry("a", "main"), &[1.0, 0.0]).unwrap();
+        index.save(&dir).unwrap();
+        assert!(Index::open_or_new(&dir, 3).is_err());
+        assert_eq!(Index::open_or_new(&dir, 2).unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn removing_a_binary_keeps_vectors_with_their_entries() {
+        let mut index = Index::new(2);
+        index.add(entry("a", "f"), &[1.0, 0.0]).unwrap();
+        index.add(entry("b", "g"), &[0.0, 1.0]).unwrap();
+        index.add(entry("a", "h"), &[1.0, 1.0]).unwrap();
+        index.remove_binary("a");
+
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.entries[0].name, "g");
+        assert_eq!(index.vectors, [0.0, 1.0]);
+    }
+
+    #[test]
+    fn search_ranks_by_similarity_and_skips() {
+        let mut index = Index::new(2);
+        index.add(entry("a", "same"), &[2.0, 0.0]).unwrap();
+        index.add(entry("b", "opposite"), &[-1.0, 0.0]).unwrap();
+        index.add(entry("b", "diagonal"), &[1.0, 1.0]).unwrap();
+        index.add(entry("c", "close"), &[1.0, 0.1]).unwrap();
+
+        let names = |hits: Vec<Hit>| hits.iter().map(|hit| hit.entry.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(index.search(&[1.0, 0.0], 3, |_| false)), ["same", "close", "diagonal"]);
+        assert_eq!(names(index.search(&[1.0, 0.0], 10, |e| e.binary == "a")), ["close", "diagonal", "opposite"]);
+        assert!((index.search(&[1.0, 0.0], 1, |_| false)[0].score - 1.0).abs() < 1e-6);
+    }
+}
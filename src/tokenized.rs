@@ -0,0 +1,186 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use burn::config::Config;
+use burn::data::dataset::Dataset;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::data::{csv_reader, split_indices, EncodedItem, MyItem};
+use crate::tokenizer::Tokenizer;
+use crate::training::TrainingConfig;
+use crate::vocab::Vocab;
+
+const MAGIC: &[u8; 8] = b"RE9KTOK\x01";
+
+// `{name}.tok` is MAGIC followed by one record per row, `[opt: u8][len: u32]`
+// and `len` token ids as u32, all little endian. `{name}.idx` holds the u64
+// offset of every record so rows can be read in any order.
+struct SplitWriter {
+    data: BufWriter<File>,
+    index: BufWriter<File>,
+    offset: u64,
+}
+
+impl SplitWriter {
+    fn create(dir: &Path, name: &str) -> Result<Self, Error> {
+        let mut data = BufWriter::new(File::create(dir.join(format!("{name}.tok")))?);
+        data.write_all(MAGIC)?;
+        let index = BufWriter::new(File::create(dir.join(format!("{name}.idx")))?);
+        Ok(Self { data, index, offset: MAGIC.len() as u64 })
+    }
+
+    fn push(&mut self, opt: u8, ids: &[usize]) -> Result<(), Error> {
+        self.index.write_all(&self.offset.to_le_bytes())?;
+        self.data.write_all(&[opt])?;
+        self.data.write_all(&(ids.len() as u32).to_le_bytes())?;
+        for id in ids {
+            self.data.write_all(&(*id as u32).to_le_bytes())?;
+        }
+        self.offset += 5 + 4 * ids.len() as u64;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), Error> {
+        self.data.flush()?;
+        self.index.flush()
+    }
+}
+
+// Split, tokenize and encode a CSV once into `out_dir`. The CSV is streamed
+// three times (labels and groups for the split, the training rows for the
+// vocab, then every row for encoding) so no pass holds the function text of
+// the whole corpus. Test rows stay text in `test.csv` for `evaluate`.
+pub fn preprocess(ds: &str, out_dir: &str, config: &TrainingConfig) -> Result<(), Error> {
+    let dir = Path::new(out_dir);
+    fs::create_dir_all(dir)?;
+    let rows = || -> Result<_, Error> {
+        Ok(csv_reader(ds)?.into_deserialize::<MyItem>())
+    };
+
+    let keys = rows()?
+        .map(|item| item.map(|item| (item.opt, item.group)))
+        .collect::<csv::Result<Vec<_>>>()?;
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let splits = split_indices(&keys, &config.split_strategy, config.split, config.test_split, &mut rng)?;
+    let mut assign = vec![0u8; keys.len()];
+    for (split, rows) in splits.iter().enumerate() {
+        rows.iter().for_each(|row| assign[*row] = split as u8);
+    }
+    drop(keys);
+
+    // the vocab is built from an iterator, a bad row stops it and is returned after
+    let tokenizer = Tokenizer::new(config.tokenizer.clone());
+    let mut failed = None;
+    let train_docs = rows()?
+        .zip(&assign)
+        .filter(|(_, split)| **split == 0)
+        .map_while(|(item, _)| match item {
+            Ok(item) => Some(tokenizer.tokenize(&item.asm)),
+            Err(e) => {
+                failed = Some(e);
+                None
+            }
+        });
+    let vocab = Vocab::build(train_docs, &config.vocab);
+    if let Some(e) = failed {
+        return Err(e.into());
+    }
+    vocab.save(&format!("{out_dir}/vocab.json"))?;
+
+    let mut train = SplitWriter::create(dir, "train")?;
+    let mut valid = SplitWriter::create(dir, "valid")?;
+    let mut test = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_path(dir.join("test.csv"))?;
+    for (item, split) in rows()?.zip(&assign) {
+        let item = item?;
+        match split {
+            0 => train.push(item.opt, &vocab.encode(&tokenizer.tokenize(&item.asm)))?,
+            1 => valid.push(item.opt, &vocab.encode(&tokenizer.tokenize(&item.asm)))?,
+            _ => test.serialize(&item)?,
+        }
+    }
+    train.finish()?;
+    valid.finish()?;
+    test.flush()?;
+
+    config
+        .save(format!("{out_dir}/config.json"))
+        .map_err(|e| Error::other(e.to_string()))
+}
+
+// Seek and read under the lock, loader workers fetch rows from several threads.
+fn read_at(file: &Mutex<File>, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+    let mut file = file.lock().map_err(|_| Error::other("tokenized split lock poisoned"))?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+// One split written by `preprocess`, rows are read from disk on demand.
+pub struct TokenizedDataset {
+    data: Mutex<File>,
+    index: Mutex<File>,
+    len: usize,
+}
+
+impl TokenizedDataset {
+    pub fn open(dir: &str, name: &str) -> Result<Self, Error> {
+        let mut data = File::open(format!("{dir}/{name}.tok"))?;
+        let mut magic = [0; MAGIC.len()];
+        data.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{dir}/{name}.tok isn't a tokenized split, rerun preprocess"),
+            ));
+        }
+
+        let index = File::open(format!("{dir}/{name}.idx"))?;
+        let len = (index.metadata()?.len() / 8) as usize;
+        Ok(Self { data: Mutex::new(data), index: Mutex::new(index), len })
+    }
+
+    pub fn labels(&self) -> Result<Vec<u8>, Error> {
+        let mut offset = [0; 8];
+        let mut label = [0; 1];
+        (0..self.len)
+            .map(|row| {
+                read_at(&self.index, &mut offset, row as u64 * 8)?;
+                read_at(&self.data, &mut label, u64::from_le_bytes(offset))?;
+                Ok(label[0])
+            })
+            .collect()
+    }
+
+    fn read(&self, row: usize) -> Result<EncodedItem, Error> {
+        let mut offset = [0; 8];
+        read_at(&self.index, &mut offset, row as u64 * 8)?;
+        let offset = u64::from_le_bytes(offset);
+
+        let mut header = [0; 5];
+        read_at(&self.data, &mut header, offset)?;
+        let len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+
+        let mut ids = vec![0; 4 * len];
+        read_at(&self.data, &mut ids, offset + 5)?;
+        let ids = ids
+            .chunks_exact(4)
+            .map(|id| u32::from_le_bytes([id[0], id[1], id[2], id[3]]) as usize)
+            .collect();
+
+        Ok(EncodedItem { opt: header[0], ids })
+    }
+}
+
+impl Dataset<EncodedItem> for TokenizedDataset {
+    fn get(&self, index: usize) -> Option<EncodedItem> {
+        (index < self.len).then(|| self.read(index).expect("read tokenized row"))
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
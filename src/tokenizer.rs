@@ -0,0 +1,309 @@
+use burn::config::Config;
+
+// With `operands` off only mnemonics are kept, which is what the shipped model
+// was trained on. The other switches only apply to operand tokens.
+#[derive(Config)]
+pub struct TokenizerConfig {
+    #[config(default = false)]
+    pub operands: bool,
+
+    #[config(default = true)]
+    pub register_classes: bool,
+
+    #[config(default = true)]
+    pub bucket_immediates: bool,
+
+    #[config(default = true)]
+    pub abstract_memory: bool,
+
+    #[config(default = true)]
+    pub resolve_calls: bool,
+}
+
+// Bumped whenever the same config would split text differently, models
+// record it in their manifest.
+pub const TOKENIZER_VERSION: u32 = 2;
+
+const CALLS: [&str; 5] = ["call", "callq", "bl", "blr", "blx"];
+
+#[derive(Clone)]
+pub struct Tokenizer {
+    config: TokenizerConfig,
+}
+
+impl Tokenizer {
+    pub fn new(config: TokenizerConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn config(&self) -> &TokenizerConfig {
+        &self.config
+    }
+
+    // Instructions are separated by newlines or ';'. Text with neither is the
+    // old whitespace separated mnemonic format.
+    pub fn tokenize(&self, text: &str) -> Vec<String> {
+        if !text.contains(['\n', ';']) && !self.config.operands {
+            return text.split_ascii_whitespace().map(str::to_string).collect();
+        }
+
+        let x86 = is_x86(text);
+        let mut out = vec![];
+        for ins in text.split(['\n', ';']) {
+            let ins = ins.trim();
+            let (mnemonic, ops) = ins.split_once(char::is_whitespace).unwrap_or((ins, ""));
+            if mnemonic.is_empty() {
+                continue;
+            }
+            out.push(mnemonic.to_string());
+            if !self.config.operands {
+                continue;
+            }
+
+            let is_call = CALLS.contains(&mnemonic);
+            for op in split_operands(ops) {
+                self.operand(op, is_call, x86, &mut out);
+            }
+        }
+        out
+    }
+
+    fn operand(&self, op: &str, is_call: bool, x86: bool, out: &mut Vec<String>) {
+        if let Some(start) = op.find(['[', '{']) {
+            // size hints like `qword ptr` stay as their own tokens
+            out.extend(op[..start].split_whitespace().map(str::to_string));
+            out.push(self.memory(&op[start..], x86));
+            return;
+        }
+        if is_call && self.config.resolve_calls {
+            out.push(self.call_target(op, x86));
+            return;
+        }
+        out.extend(op.split_whitespace().map(|word| self.atom(word, x86)));
+    }
+
+    fn memory(&self, mem: &str, x86: bool) -> String {
+        let (open, close) = match mem.starts_with('{') {
+            true => ('{', '}'),
+            false => ('[', ']'),
+        };
+        let inner = mem
+            .trim_start_matches(open)
+            .trim_end_matches('!')
+            .trim_end_matches(close);
+
+        let parts = inner
+            .split([' ', '+', '-', '*', ','])
+            .filter(|part| !part.is_empty())
+            .map(|part| match self.config.abstract_memory {
+                true => match reg_class(part, x86) {
+                    Some(class @ ("sp" | "pc" | "fp")) => class.to_string(),
+                    Some(_) => "reg".to_string(),
+                    None if parse_imm(part).is_some() => "imm".to_string(),
+                    None => "sym".to_string(),
+                },
+                false => self.atom(part, x86),
+            })
+            .collect::<Vec<_>>();
+
+        format!("{open}{}{close}", parts.join("+"))
+    }
+
+    // Library imports keep their name, everything else is `extern`.
+    fn call_target(&self, op: &str, x86: bool) -> String {
+        let op = op.trim();
+        if let Some(class) = reg_class(op, x86) {
+            return self.register(op, class);
+        }
+        for prefix in ["sym.imp.", "reloc."] {
+            if let Some(name) = op.strip_prefix(prefix) {
+                return name.to_string();
+            }
+        }
+        "extern".to_string()
+    }
+
+    fn atom(&self, word: &str, x86: bool) -> String {
+        let word = word.trim_end_matches('!');
+        if let Some(class) = reg_class(word, x86) {
+            return self.register(word, class);
+        }
+        if let Some(value) = parse_imm(word) {
+            return self.immediate(value);
+        }
+        match word.split_once('.') {
+            Some((kind @ ("sym" | "fcn" | "str" | "obj" | "loc" | "reloc"), _)) => kind.to_string(),
+            _ => word.to_string(),
+        }
+    }
+
+    fn register(&self, name: &str, class: &'static str) -> String {
+        match self.config.register_classes {
+            true => class.to_string(),
+            false => name.to_string(),
+        }
+    }
+
+    fn immediate(&self, value: i128) -> String {
+        if !self.config.bucket_immediates {
+            return "imm".to_string();
+        }
+        let sign = if value < 0 { "-" } else { "" };
+        match value.unsigned_abs() {
+            0 => "0".to_string(),
+            1 => format!("{sign}1"),
+            v if v <= u8::MAX as u128 => format!("{sign}imm8"),
+            v if v <= u16::MAX as u128 => format!("{sign}imm16"),
+            v if v <= u32::MAX as u128 => format!("{sign}imm32"),
+            _ => format!("{sign}imm64"),
+        }
+    }
+}
+
+// Commas inside `[x1, 8]` or `{r4, lr}` don't separate operands.
+fn split_operands(ops: &str) -> Vec<&str> {
+    let mut out = vec![];
+    let mut depth = 0i32;
+    let mut start = 0;
+    for (idx, c) in ops.char_indices() {
+        match c {
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                out.push(ops[start..idx].trim());
+                start = idx + 1;
+            }
+            _ => (),
+        }
+    }
+    out.push(ops[start..].trim());
+    out.retain(|op| !op.is_empty());
+    out
+}
+
+fn parse_imm(word: &str) -> Option<i128> {
+    let word = word.trim_start_matches('#');
+    let (neg, digits) = match word.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, word),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i128::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i128>().ok()?,
+    };
+    Some(if neg { -value } else { value })
+}
+
+fn numbered(name: &str, prefix: &str, max: u32) -> bool {
+    name.strip_prefix(prefix)
+        .and_then(|n| n.parse::<u32>().ok())
+        .is_some_and(|n| n <= max)
+}
+
+const X86_ONLY: [&str; 18] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rsp", "rbp", "rip", "eax", "ebx", "ecx", "edx", "esi", "edi", "esp",
+    "ebp", "eip",
+];
+
+// Whether `text` names a register only x86 has. Short names like `bl` or `cs`
+// are left out, they are also ARM mnemonics and condition codes.
+fn is_x86(text: &str) -> bool {
+    text.split(|c: char| !c.is_ascii_alphanumeric()).any(|word| {
+        X86_ONLY.contains(&word)
+            || ["r13", "r14", "r15", "xmm", "ymm", "zmm"].iter().any(|p| word.starts_with(p))
+            || word.strip_suffix(['d', 'w', 'b']).is_some_and(|r| numbered(r, "r", 15) && !numbered(r, "r", 7))
+    })
+}
+
+// x86, AArch64 and ARM register names by class. `r8`-`r12` are 64-bit
+// registers in x86 code and 32-bit ones in ARM code, `r13`-`r15` are x86 only.
+fn reg_class(name: &str, x86: bool) -> Option<&'static str> {
+    let class = match name {
+        "rip" | "eip" | "pc" => "pc",
+        "rsp" | "esp" | "sp" | "wsp" => "sp",
+        "rbp" | "ebp" | "fp" | "x29" => "fp",
+        "lr" | "x30" => "lr",
+        "xzr" | "wzr" => "zr",
+        "rax" | "rbx" | "rcx" | "rdx" | "rsi" | "rdi" => "reg64",
+        "eax" | "ebx" | "ecx" | "edx" | "esi" | "edi" | "ip" | "sl" => "reg32",
+        "ax" | "bx" | "cx" | "dx" | "si" | "di" | "bp" => "reg16",
+        "al" | "bl" | "cl" | "dl" | "ah" | "bh" | "ch" | "dh" | "sil" | "dil" | "bpl" | "spl" => "reg8",
+        "cs" | "ds" | "es" | "fs" | "gs" | "ss" => "seg",
+        _ if numbered(name, "r", 7) => "reg32",
+        _ if numbered(name, "r", 12) && !x86 => "reg32",
+        _ if numbered(name, "r", 15) => "reg64",
+        _ if name.starts_with('r') && name.ends_with('d') && numbered(&name[..name.len() - 1], "r", 15) => "reg32",
+        _ if name.starts_with('r') && name.ends_with('w') && numbered(&name[..name.len() - 1], "r", 15) => "reg16",
+        _ if name.starts_with('r') && name.ends_with('b') && numbered(&name[..name.len() - 1], "r", 15) => "reg8",
+        _ if numbered(name, "x", 28) => "reg64",
+        _ if numbered(name, "w", 30) => "reg32",
+        _ if numbered(name, "xmm", 31) => "xmm",
+        _ if numbered(name, "ymm", 31) => "ymm",
+        _ if numbered(name, "zmm", 31) => "zmm",
+        _ if numbered(name, "st", 7) || name == "st" => "fpu",
+        _ if numbered(name, "v", 31) || numbered(name, "q", 31) => "vec",
+        _ if ["d", "s", "h", "b"].iter().any(|p| numbered(name, p, 31)) => "fp_reg",
+        _ => return None,
+    };
+    Some(class)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(text: &str) -> Vec<String> {
+        Tokenizer::new(TokenizerConfig::new().with_operands(true)).tokenize(text)
+    }
+
+    #[test]
+    fn mnemonics_only_by_default() {
+        let tokenizer = Tokenizer::new(TokenizerConfig::new());
+        assert_eq!(tokenizer.tokenize("push rbp\nmov rbp, rsp"), ["push", "mov"]);
+        assert_eq!(tokenizer.tokenize("push mov ret"), ["push", "mov", "ret"]);
+    }
+
+    #[test]
+    fn x86_operands() {
+        assert_eq!(
+            tokens("mov rax, qword [rbp - 0x18]; add r8d, 1; call sym.imp.malloc; call fcn.00401000; xor r9, r9"),
+            [
+                "mov", "reg64", "qword", "[fp+imm]", "add", "reg32", "1", "call", "malloc", "call", "extern", "xor",
+                "reg64", "reg64",
+            ]
+        );
+    }
+
+    #[test]
+    fn aarch64_operands() {
+        assert_eq!(
+            tokens("stp x29, x30, [sp, -0x10]!\nldr w0, [x1, 8]\nmov x0, 0x12345\nbl sym.imp.puts"),
+            ["stp", "fp", "lr", "[sp+imm]", "ldr", "reg32", "[reg+imm]", "mov", "reg64", "imm32", "bl", "puts"]
+        );
+    }
+
+    #[test]
+    fn arm_operands() {
+        assert_eq!(
+            tokens("push {r4, r8, lr}\nmov r9, #-0x100\nadd r12, sl, r3\npop {r4, r8, pc}"),
+            [
+                "push", "{reg+reg+reg}", "mov", "reg32", "-imm16", "add", "reg32", "reg32", "reg32", "pop",
+                "{reg+reg+pc}",
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_commas_stay_in_their_operand() {
+        assert_eq!(split_operands("x0, [x1, 8], {r4, lr}"), ["x0", "[x1, 8]", "{r4, lr}"]);
+    }
+
+    #[test]
+    fn immediates_bucket_by_size() {
+        let tokenizer = Tokenizer::new(TokenizerConfig::new());
+        let buckets = [0, 1, -1, 0xff, 0x100, -0x10000, 1 << 40].map(|v| tokenizer.immediate(v));
+        assert_eq!(buckets, ["0", "1", "-1", "imm8", "imm16", "-imm32", "imm64"]);
+        assert_eq!(parse_imm("#-0x10"), Some(-0x10));
+        assert_eq!(parse_imm("r0"), None);
+    }
+}
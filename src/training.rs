@@ -1,19 +1,69 @@
-use crate::data::{MyDataset, ItemBatcher};
+use crate::data::{Sampling, SplitStrategy, Truncation};
 use crate::model::ModelConfig;
+use crate::schedule::LrSchedule;
+use crate::tokenizer::TokenizerConfig;
+use crate::vocab::VocabConfig;
 use burn::{
-    data::dataloader::DataLoaderBuilder,
-    optim::AdamConfig,
+    optim::{AdamWConfig, GradientClippingConfig},
     prelude::*,
-    record::DefaultRecorder,
-    record::CompactRecorder,
-    tensor::backend::AutodiffBackend,
-    train::{
-        metric::store::{Aggregate, Direction, Split},
-        metric::{AccuracyMetric, CudaMetric, LossMetric},
-        LearnerBuilder, MetricEarlyStoppingStrategy, StoppingCondition,
+};
+
+// Everything past the config needs the train feature.
+#[cfg(feature = "train")]
+use {
+    crate::data::{MyDataset, ItemBatch, ItemBatcher, Oversampled},
+    crate::evaluation::F1Metric,
+    crate::manifest::Manifest,
+    crate::model::LossSettings,
+    crate::schedule::Scheduler,
+    crate::tokenized::TokenizedDataset,
+    crate::tokenizer::Tokenizer,
+    crate::vocab::Vocab,
+    std::io::{Error, ErrorKind},
+    std::path::Path,
+    std::sync::Arc,
+    burn::{
+        data::dataloader::{batcher::Batcher, DataLoaderBuilder},
+        data::dataset::Dataset,
+        record::DefaultRecorder,
+        record::CompactRecorder,
+        record::Recorder,
+        tensor::backend::AutodiffBackend,
+        train::{
+            checkpoint::{ComposedCheckpointingStrategy, KeepLastNCheckpoints, MetricCheckpointingStrategy},
+            metric::store::{Aggregate, Direction, Split},
+            metric::{AccuracyMetric, CudaMetric, LossMetric, Metric},
+            LearnerBuilder, MetricEarlyStoppingStrategy, StoppingCondition,
+        },
     },
 };
 
+// Validation metric that decides early stopping and which epoch is saved.
+#[derive(Config)]
+pub enum StopMetric {
+    Loss,
+    Accuracy,
+    F1,
+}
+
+impl StopMetric {
+    pub fn lowest(&self) -> bool {
+        matches!(self, StopMetric::Loss)
+    }
+
+    // what the metric is logged as
+    #[cfg(feature = "train")]
+    pub fn name<B: Backend>(&self) -> &'static str {
+        match self {
+            StopMetric::Loss => LossMetric::<B>::NAME,
+            StopMetric::Accuracy => AccuracyMetric::<B>::NAME,
+            StopMetric::F1 => F1Metric::<B>::NAME,
+        }
+    }
+}
+
+pub const OPT_LABELS: [&str; 2] = ["unoptimized", "optimized"];
+
 #[derive(Config)]
 pub struct TrainingConfig {
     #[config(default = 10)]
@@ -25,51 +75,283 @@ pub struct TrainingConfig {
     #[config(default = 64)]
     pub sequence_length: usize,
 
+    #[config(default = "Truncation::Head")]
+    pub truncation: Truncation,
+
+    #[config(default = false)]
+    pub mask_padding: bool,
+
     #[config(default = 1)]
     pub num_workers: usize,
 
     #[config(default = 1e-3)]
     pub learning_rate: f64,
 
-    pub optimizer: AdamConfig,
+    #[config(default = 31337)]
+    pub seed: u64,
+
+    // percentages of rows used for training and held out for testing, the
+    // rest is validation
+    #[config(default = 80)]
+    pub split: usize,
+
+    #[config(default = 0)]
+    pub test_split: usize,
+
+    #[config(default = "SplitStrategy::Random")]
+    pub split_strategy: SplitStrategy,
+
+    // weight the loss by inverse label frequency in the training split
+    #[config(default = false)]
+    pub class_weights: bool,
+
+    // focal loss with this gamma instead of cross entropy
+    pub focal_gamma: Option<f64>,
+
+    #[config(default = "Sampling::Shuffle")]
+    pub sampling: Sampling,
+
+    // AdamW, a weight_decay of 0 makes it plain Adam
+    pub optimizer: AdamWConfig,
+
+    // epochs without improvement of `stop_metric` before stopping
+    #[config(default = 3)]
+    pub patience: usize,
+
+    #[config(default = "StopMetric::Loss")]
+    pub stop_metric: StopMetric,
+
+    #[config(default = "LrSchedule::Constant")]
+    pub lr_schedule: LrSchedule,
+
+    #[config(default = 0)]
+    pub warmup_steps: usize,
 
     pub vocab_size: Option<usize>,
     pub model_conf: ModelConfig,
+
+    #[config(default = "VocabConfig::new()")]
+    pub vocab: VocabConfig,
+
+    #[config(default = "TokenizerConfig::new()")]
+    pub tokenizer: TokenizerConfig,
+
+    // name of every label id, in id order
+    #[config(default = "OPT_LABELS.iter().map(|l| l.to_string()).collect()")]
+    pub labels: Vec<String>,
 }
 
-fn create_artifact_dir(artifact_dir: &str) {
+pub const DEFAULT_ARTIFACT_DIR: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/assets");
+
+// The settings a fresh run starts from, vocab_size is filled in from the data.
+pub fn default_config() -> TrainingConfig {
+    let model = ModelConfig::new(
+        0, //vocab_size
+        256, //embedding_dim
+        128, //lstm_units
+        64); //batch_size
+
+    let optimizer = AdamWConfig::new()
+        .with_weight_decay(0.0)
+        .with_grad_clipping(Some(GradientClippingConfig::Norm(1.0)));
+
+    TrainingConfig::new(optimizer, model)
+        .with_mask_padding(true)
+}
+
+#[cfg(feature = "train")]
+fn create_artifact_dir(artifact_dir: &str, resume: bool) -> Result<(), Error> {
     let path = std::path::Path::new(artifact_dir);
-    if path.exists() {
-        let mut dir_entries = std::fs::read_dir(artifact_dir)
-            .unwrap_or_else(|_| panic!("Error reading directory"));
-        if !dir_entries.next().is_none() {
-            panic!("Artifact directory not empty!");
+    if path.exists() && !resume {
+        let mut dir_entries = std::fs::read_dir(artifact_dir)?;
+        if dir_entries.next().is_some() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("artifact directory {artifact_dir} isn't empty, resume or pick another one"),
+            ));
         }
     }
-    std::fs::create_dir_all(artifact_dir).ok();
+    std::fs::create_dir_all(artifact_dir)
+}
+
+// Highest epoch the file checkpointer left in `{artifact_dir}/checkpoint`.
+pub fn latest_checkpoint(artifact_dir: &str) -> Option<usize> {
+    std::fs::read_dir(format!("{artifact_dir}/checkpoint"))
+        .ok()?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            name.strip_prefix("model-")?.strip_suffix(".mpk")?.parse().ok()
+        })
+        .max()
 }
 
-pub fn run<B: AutodiffBackend>(devices: Vec<B::Device>, ds_train: &str) {
-    let artifact_dir = concat!(
-        env!("CARGO_MANIFEST_DIR"),
-        "/assets");
-    create_artifact_dir(&artifact_dir);
+#[cfg(feature = "train")]
+pub fn run<B: AutodiffBackend>(
+    devices: Vec<B::Device>,
+    ds_train: &str,
+    config: TrainingConfig,
+    artifact_dir: &str,
+    resume: Option<usize>,
+) -> Result<(), Error> {
+    create_artifact_dir(artifact_dir, resume.is_some())?;
 
-    let seed = 31337;
+    let seed = config.seed;
     B::seed(seed);
-    let (dataset_a, dataset_b) = MyDataset::new(&ds_train, 80, seed, &artifact_dir).expect("Could not load diabetes dataset");
+    let mut config = config;
 
-    let vocab_size = dataset_a.get_vocab_size();
-    let model = ModelConfig::new(
-        vocab_size+1, //vocab_size
-        256, //embedding_dim
-        128, //lstm_units
-        64); //batch_size
+    // a directory is the output of `preprocess`, its data settings win so the
+    // saved config tokenizes like the encoded rows were
+    if Path::new(ds_train).is_dir() {
+        let prep = TrainingConfig::load(format!("{ds_train}/config.json"))
+            .map_err(|e| Error::other(e.to_string()))?;
+        config.split = prep.split;
+        config.test_split = prep.test_split;
+        config.split_strategy = prep.split_strategy;
+        config.vocab = prep.vocab;
+        config.tokenizer = prep.tokenizer;
+        config.labels = prep.labels;
+        std::fs::copy(format!("{ds_train}/vocab.json"), format!("{artifact_dir}/vocab.json"))?;
+        let vocab = Arc::new(Vocab::load(&format!("{artifact_dir}/vocab.json"))?);
+        config.model_conf.vocab_size = vocab.len();
+
+        let dataset_a = TokenizedDataset::open(ds_train, "train")?;
+        let dataset_b = TokenizedDataset::open(ds_train, "valid")?;
+        let labels = dataset_a.labels()?;
+        fit::<B, _, _>(devices, config.clone(), vocab.clone(), &labels, artifact_dir, resume, dataset_a, dataset_b)?;
+        return write_manifest::<B>(ds_train, &config, &vocab, artifact_dir);
+    }
 
-    let config_optimizer = AdamConfig::new();
-    let config = TrainingConfig::new(config_optimizer, model.clone());
-    let batcher_train = ItemBatcher::<B>::new(devices[0].clone(), &artifact_dir, config.sequence_length);
-    let batcher_test = ItemBatcher::<B::InnerBackend>::new(devices[0].clone(), &artifact_dir, config.sequence_length);
+    let tokenizer = Tokenizer::new(config.tokenizer.clone());
+    let (dataset_a, dataset_b) = MyDataset::new(ds_train, &config, artifact_dir, &tokenizer)?;
+    config.model_conf.vocab_size = dataset_a.get_vocab_size(); //specials included
+
+    let vocab = dataset_a.get_vocab();
+    let labels = dataset_a.labels();
+
+    fit::<B, _, _>(devices, config.clone(), vocab.clone(), &labels, artifact_dir, resume, dataset_a, dataset_b)?;
+    write_manifest::<B>(ds_train, &config, &vocab, artifact_dir)
+}
+
+#[cfg(feature = "train")]
+fn write_manifest<B: Backend>(ds_train: &str, config: &TrainingConfig, vocab: &Vocab, artifact_dir: &str) -> Result<(), Error> {
+    Manifest::build(ds_train, config, vocab, artifact_dir, config.stop_metric.name::<B>())?.save(artifact_dir)
+}
+
+// Inverse label frequency, a label makes up 1/weight of an even share of rows.
+#[cfg(feature = "train")]
+fn class_weights(labels: &[u8]) -> Vec<f32> {
+    let mut counts = vec![0usize; labels.iter().max().map_or(0, |l| *l as usize + 1)];
+    labels.iter().for_each(|label| counts[*label as usize] += 1);
+    let seen = counts.iter().filter(|n| **n > 0).count();
+
+    counts
+        .iter()
+        .map(|n| match n {
+            0 => 1.0,
+            n => labels.len() as f32 / (seen * n) as f32,
+        })
+        .collect()
+}
+
+// Stop on `M` and keep its best checkpoint next to the last two.
+#[cfg(feature = "train")]
+fn stopping<M: Metric>(lowest: bool, patience: usize) -> (MetricEarlyStoppingStrategy, ComposedCheckpointingStrategy) {
+    let direction = || if lowest { Direction::Lowest } else { Direction::Highest };
+    let early_stopping = MetricEarlyStoppingStrategy::new::<M>(
+        Aggregate::Mean,
+        direction(),
+        Split::Valid,
+        StoppingCondition::NoImprovementSince { n_epochs: patience.max(1) },
+    );
+    let checkpointing = ComposedCheckpointingStrategy::builder()
+        .add(KeepLastNCheckpoints::new(2))
+        .add(MetricCheckpointingStrategy::new::<M>(Aggregate::Mean, direction(), Split::Valid))
+        .build();
+    (early_stopping, checkpointing)
+}
+
+// Mean validation value of an epoch according to the file metric logs,
+// `{artifact_dir}/valid/epoch-N/{name}.log` with a `value,count` line per batch.
+pub fn validation_mean(artifact_dir: &str, name: &str, epoch: usize) -> Option<f64> {
+    let log = std::fs::read_to_string(format!("{artifact_dir}/valid/epoch-{epoch}/{name}.log")).ok()?;
+    let (sum, count) = log
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split(',');
+            let value = parts.next()?.trim().parse::<f64>().ok()?;
+            let count = parts.next().and_then(|n| n.trim().parse::<f64>().ok()).unwrap_or(1.0);
+            Some((value * count, count))
+        })
+        .fold((0.0, 0.0), |acc, (sum, count)| (acc.0 + sum, acc.1 + count));
+    (count > 0.0).then(|| sum / count)
+}
+
+// Epoch with the best mean validation value, and that value.
+pub fn best_validation(artifact_dir: &str, name: &str, lowest: bool) -> Option<(usize, f64)> {
+    std::fs::read_dir(format!("{artifact_dir}/valid"))
+        .ok()?
+        .filter_map(|entry| {
+            let epoch = entry.ok()?.file_name().into_string().ok()?.strip_prefix("epoch-")?.parse::<usize>().ok()?;
+            Some((epoch, validation_mean(artifact_dir, name, epoch)?))
+        })
+        .max_by(|a, b| match lowest {
+            true => b.1.total_cmp(&a.1),
+            false => a.1.total_cmp(&b.1),
+        })
+}
+
+#[cfg(feature = "train")]
+fn fit<B, I, D>(
+    devices: Vec<B::Device>,
+    config: TrainingConfig,
+    vocab: Arc<Vocab>,
+    labels: &[u8],
+    artifact_dir: &str,
+    resume: Option<usize>,
+    dataset_a: D,
+    dataset_b: D,
+) -> Result<(), Error>
+where
+    B: AutodiffBackend,
+    I: Send + Sync + Clone + std::fmt::Debug + 'static,
+    D: Dataset<I> + 'static,
+    ItemBatcher<B>: Batcher<I, ItemBatch<B>>,
+    ItemBatcher<B::InnerBackend>: Batcher<I, ItemBatch<B::InnerBackend>>,
+{
+    let seed = config.seed;
+    // one output per configured label, rows labeled past them can't be learned
+    if let Some(label) = labels.iter().find(|label| **label as usize >= config.labels.len()) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("training rows are labeled {label}, the configured labels are {:?}", config.labels),
+        ));
+    }
+    let mut config = config;
+    config.model_conf.num_classes = Some(config.labels.len());
+    config.save(format!("{artifact_dir}/config.json"))?;
+    let model = config.model_conf.clone();
+    let loss = LossSettings {
+        class_weights: config.class_weights.then(|| class_weights(labels)),
+        focal_gamma: config.focal_gamma,
+    };
+    let tokenizer = Tokenizer::new(config.tokenizer.clone());
+
+    let batcher_train = ItemBatcher::<B>::new(devices[0].clone(), vocab.clone(), config.sequence_length, config.truncation.clone(), config.mask_padding, tokenizer.clone());
+    let batcher_test = ItemBatcher::<B::InnerBackend>::new(devices[0].clone(), vocab, config.sequence_length, config.truncation.clone(), config.mask_padding, tokenizer);
+
+    let dataset_a: Arc<dyn Dataset<I>> = match config.sampling {
+        Sampling::Shuffle => Arc::new(dataset_a),
+        Sampling::Oversample => Arc::new(Oversampled::new(dataset_a, labels, seed)),
+    };
+    let scheduler = Scheduler::new(
+        config.lr_schedule.clone(),
+        config.learning_rate,
+        config.warmup_steps,
+        dataset_a.len().div_ceil(config.batch_size),
+        config.num_epochs,
+    );
 
     let dataloader_train = DataLoaderBuilder::new(batcher_train)
         .batch_size(config.batch_size)
@@ -83,7 +365,15 @@ pub fn run<B: AutodiffBackend>(devices: Vec<B::Device>, ds_train: &str) {
         .shuffle(seed)
         .build(dataset_b);
 
-    let learner = LearnerBuilder::new(&artifact_dir)
+    let lowest = config.stop_metric.lowest();
+    let metric_name = config.stop_metric.name::<B>();
+    let (early_stopping, checkpointing) = match config.stop_metric {
+        StopMetric::Loss => stopping::<LossMetric<B>>(lowest, config.patience),
+        StopMetric::Accuracy => stopping::<AccuracyMetric<B>>(lowest, config.patience),
+        StopMetric::F1 => stopping::<F1Metric<B>>(lowest, config.patience),
+    };
+
+    let mut builder = LearnerBuilder::new(artifact_dir)
         .metric_train(CudaMetric::new())
         .metric_valid(CudaMetric::new())
         .metric_train_numeric(AccuracyMetric::new())
@@ -91,29 +381,39 @@ pub fn run<B: AutodiffBackend>(devices: Vec<B::Device>, ds_train: &str) {
         .metric_train_numeric(LossMetric::new())
         .metric_valid_numeric(LossMetric::new())
         .with_file_checkpointer(DefaultRecorder::new())
-        .early_stopping(MetricEarlyStoppingStrategy::new::<LossMetric<B>>(
-            Aggregate::Mean,
-            Direction::Lowest,
-            Split::Valid,
-            StoppingCondition::NoImprovementSince { n_epochs: 1 },
-        ))
+        .metric_valid_numeric(F1Metric::new())
+        .early_stopping(early_stopping)
+        .with_checkpointing_strategy(checkpointing)
         .devices(devices.clone())
         .num_epochs(config.num_epochs)
-        .summary()
+        .summary();
+    if let Some(epoch) = resume {
+        builder = builder.checkpoint(epoch);
+    }
+
+    let learner = builder
         .build(
-            model.init(&devices[0]),
+            model.init(&devices[0]).with_loss(loss),
             config.optimizer.init(),
-            config.learning_rate,
+            scheduler,
         );
 
-    let model_trained = learner.fit(dataloader_train, dataloader_test);
+    let mut model_trained = learner.fit(dataloader_train, dataloader_test);
+
+    // the last epoch is usually past the best one once early stopping kicks in
+    if let Some((epoch, _)) = best_validation(artifact_dir, metric_name, lowest) {
+        let path = format!("{artifact_dir}/checkpoint/model-{epoch}");
+        match Recorder::<B::InnerBackend>::load(&DefaultRecorder::new(), path.into(), &devices[0]) {
+            Ok(record) => {
+                model_trained = model_trained.load_record(record);
+                eprintln!("restored epoch {epoch}, best validation {metric_name}");
+            }
+            Err(e) => eprintln!("keeping the last epoch, checkpoint {epoch} didn't load: {e}"),
+        }
+    }
     model_trained
         .save_file(format!("{artifact_dir}/model"), &CompactRecorder::new())
-        .expect("Trained model should be saved successfully");
-
-    config
-        .save(format!("{artifact_dir}/config.json"))
-        .expect("saved config file");
-
+        .map_err(|e| Error::other(e.to_string()))?;
 
+    Ok(())
 }
@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind};
+
+use burn::tensor::backend::AutodiffBackend;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::training::{self, TrainingConfig};
+
+// Candidate values per config field, keyed by its path in config.json, e.g.
+// `{"learning_rate": [1e-4, 1e-3], "model_conf.lstm_dim": [64, 128]}`.
+pub type Space = BTreeMap<String, Vec<Value>>;
+
+pub enum Search {
+    Grid,
+    Random { trials: usize },
+}
+
+#[derive(Serialize)]
+pub struct Trial {
+    pub dir: String,
+    pub params: BTreeMap<String, Value>,
+    pub epoch: Option<usize>,
+    pub score: Option<f64>,
+}
+
+pub struct Leaderboard {
+    pub metric: &'static str,
+    pub trials: Vec<Trial>,
+}
+
+pub fn load_space(path: &str) -> Result<Space, Error> {
+    let space: Space = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    match space.iter().find(|(_, values)| values.is_empty()) {
+        Some((key, _)) => Err(Error::new(ErrorKind::InvalidInput, format!("{key} has no values to try"))),
+        None => Ok(space),
+    }
+}
+
+fn grid(space: &Space) -> Vec<BTreeMap<String, Value>> {
+    let mut points = vec![BTreeMap::new()];
+    for (key, values) in space {
+        points = points
+            .into_iter()
+            .flat_map(|point| {
+                values.iter().map(move |value| {
+                    let mut point = point.clone();
+                    point.insert(key.clone(), value.clone());
+                    point
+                })
+            })
+            .collect();
+    }
+    points
+}
+
+fn random(space: &Space, trials: usize, seed: u64) -> Vec<BTreeMap<String, Value>> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..trials)
+        .map(|_| {
+            space
+                .iter()
+                .filter_map(|(key, values)| Some((key.clone(), values.choose(&mut rng)?.clone())))
+                .collect()
+        })
+        .collect()
+}
+
+// Set every `params` path in the JSON form of `base`. Unknown paths are an
+// error so a typo doesn't silently tune nothing.
+pub fn apply(base: &TrainingConfig, params: &BTreeMap<String, Value>) -> Result<TrainingConfig, Error> {
+    let mut json = serde_json::to_value(base)?;
+    for (path, value) in params {
+        let slot = path
+            .split('.')
+            .try_fold(&mut json, |node, key| node.get_mut(key))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{path} isn't a config field")))?;
+        *slot = value.clone();
+    }
+    Ok(serde_json::from_value(json)?)
+}
+
+// Train one model per point of the search under `{artifact_dir}/trial-N` and
+// rank them by the best validation value of the config's stop metric. The
+// winner's config is copied to `{artifact_dir}/config.json`.
+pub fn run<B: AutodiffBackend>(
+    devices: Vec<B::Device>,
+    ds_train: &str,
+    base: TrainingConfig,
+    space: &Space,
+    search: Search,
+    artifact_dir: &str,
+) -> Result<Leaderboard, Error> {
+    std::fs::create_dir_all(artifact_dir)?;
+    let points = match search {
+        Search::Grid => grid(space),
+        Search::Random { trials } => random(space, trials, base.seed),
+    };
+    let metric = base.stop_metric.name::<B>();
+    let lowest = base.stop_metric.lowest();
+
+    let mut trials = vec![];
+    for (idx, params) in points.into_iter().enumerate() {
+        let config = apply(&base, &params)?;
+        let dir = format!("{artifact_dir}/trial-{idx:03}");
+        eprintln!("trial {idx}: {}", serde_json::to_string(&params)?);
+
+        let best = match training::run::<B>(devices.clone(), ds_train, config, &dir, None) {
+            Ok(()) => training::best_validation(&dir, metric, lowest),
+            Err(e) => {
+                eprintln!("trial {idx} failed: {e}");
+                None
+            }
+        };
+        trials.push(Trial {
+            dir,
+            params,
+            epoch: best.map(|(epoch, _)| epoch),
+            score: best.map(|(_, score)| score),
+        });
+    }
+
+    // failed trials go last
+    trials.sort_by(|a, b| match (a.score, b.score) {
+        (Some(a), Some(b)) if lowest => a.total_cmp(&b),
+        (Some(a), Some(b)) => b.total_cmp(&a),
+        (a, b) => b.is_some().cmp(&a.is_some()),
+    });
+
+    if let Some(best) = trials.first().filter(|trial| trial.score.is_some()) {
+        std::fs::copy(format!("{}/config.json", best.dir), format!("{artifact_dir}/config.json"))?;
+    }
+    let board = Leaderboard { metric, trials };
+    serde_json::to_writer_pretty(File::create(format!("{artifact_dir}/leaderboard.json"))?, &board.trials)?;
+    Ok(board)
+}
+
+impl fmt::Display for Leaderboard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:>5}{:>12}{:>7}  {:<20}params", "rank", self.metric, "epoch", "dir")?;
+        for (rank, trial) in self.trials.iter().enumerate() {
+            let score = trial.score.map_or("failed".to_string(), |s| format!("{s:.4}"));
+            let epoch = trial.epoch.map_or("-".to_string(), |e| e.to_string());
+            let params = serde_json::to_string(&trial.params).unwrap_or_default();
+            let dir = trial.dir.rsplit('/').next().unwrap_or(&trial.dir);
+            writeln!(f, "{:>5}{score:>12}{epoch:>7}  {dir:<20}{params}", rank + 1)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn grid_tries_every_combination() {
+        let space = Space::from([
+            ("learning_rate".to_string(), vec![json!(1e-4), json!(1e-3), json!(1e-2)]),
+            ("model_conf.lstm_dim".to_string(), vec![json!(64), json!(128)]),
+        ]);
+        let points = grid(&space);
+        assert_eq!(points.len(), 6);
+        assert!(points.iter().all(|point| point.len() == 2));
+        assert!(points.iter().enumerate().all(|(i, point)| !points[..i].contains(point)));
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let base = training::default_config();
+        let params = BTreeMap::from([("learning_rate".to_string(), json!(0.01))]);
+        assert_eq!(apply(&base, &params).unwrap().learning_rate, 0.01);
+
+        let params = BTreeMap::from([("model_conf.lstm_units".to_string(), json!(64))]);
+        let err = apply(&base, &params).map(|_| ()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind};
+
+use burn::config::Config;
+use serde::{Deserialize, Serialize};
+
+pub const PAD: usize = 0;
+pub const UNK: usize = 1;
+pub const CLS: usize = 2;
+pub const SEP: usize = 3;
+pub const SPECIALS: [&str; 4] = ["<pad>", "<unk>", "<cls>", "<sep>"];
+
+const VERSION: u32 = 1;
+
+#[derive(Config)]
+pub struct VocabConfig {
+    #[config(default = 1)]
+    pub min_freq: usize,
+
+    pub max_size: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum OnDisk {
+    Versioned {
+        version: u32,
+        specials: Vec<String>,
+        tokens: HashMap<String, usize>,
+    },
+    // plain token -> id map, ids start at 1 and 0 is padding. Id 1 is a real
+    // token here, so unknowns map to padding like they were trained.
+    Legacy(HashMap<String, usize>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Vocab {
+    version: u32,
+    tokens: HashMap<String, usize>,
+}
+
+impl Vocab {
+    pub fn build(docs: impl Iterator<Item = Vec<String>>, config: &VocabConfig) -> Self {
+        let mut freq = HashMap::new();
+        for doc in docs {
+            doc.into_iter()
+                .for_each(|word| *freq.entry(word).or_insert(0usize) += 1);
+        }
+
+        let mut words = freq
+            .into_iter()
+            .filter(|(_, n)| *n >= config.min_freq)
+            .collect::<Vec<_>>();
+        // most frequent first, ties by name so ids are stable across runs
+        words.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        if let Some(max_size) = config.max_size {
+            words.truncate(max_size.saturating_sub(SPECIALS.len()));
+        }
+
+        let tokens = SPECIALS
+            .iter()
+            .map(|s| s.to_string())
+            .chain(words.into_iter().map(|(word, _)| word))
+            .enumerate()
+            .map(|(id, word)| (word, id))
+            .collect();
+
+        Self { version: VERSION, tokens }
+    }
+
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let reader = BufReader::new(File::open(path)?);
+        match serde_json::from_reader(reader)? {
+            OnDisk::Versioned { version, .. } if version > VERSION => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("vocab version {version} is newer than supported {VERSION}"),
+            )),
+            OnDisk::Versioned { version, tokens, .. } => Ok(Self { version, tokens }),
+            OnDisk::Legacy(tokens) => Ok(Self { version: 0, tokens }),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        let file = File::create(path)?;
+        let on_disk = OnDisk::Versioned {
+            version: self.version,
+            specials: SPECIALS.iter().map(|s| s.to_string()).collect(),
+            tokens: self.tokens.clone(),
+        };
+        serde_json::to_writer(file, &on_disk)?;
+        Ok(())
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    // Number of embedding rows needed, padding and specials included.
+    pub fn len(&self) -> usize {
+        self.tokens.values().max().map_or(SPECIALS.len(), |id| id + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    // Id of words missing from the vocab.
+    pub fn unknown(&self) -> usize {
+        match self.version {
+            0 => PAD,
+            _ => UNK,
+        }
+    }
+
+    pub fn id(&self, word: &str) -> usize {
+        self.tokens.get(word).copied().unwrap_or(self.unknown())
+    }
+
+    pub fn encode(&self, tokens: &[String]) -> Vec<usize> {
+        tokens.iter().map(|word| self.id(word)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Vec<String> {
+        text.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn unknowns_get_unk() {
+        let vocab = Vocab::build([words("mov add mov")].into_iter(), &VocabConfig::new());
+        assert_eq!(vocab.encode(&words("mov xor")), vec![SPECIALS.len(), UNK]);
+    }
+
+    #[test]
+    fn legacy_unknowns_get_padding() {
+        let tokens = [("mov", 2), ("add", 1)].map(|(w, id)| (w.to_string(), id)).into();
+        let vocab = Vocab { version: 0, tokens };
+        assert_eq!(vocab.encode(&words("add xor")), vec![1, PAD]);
+    }
+}
@@ -0,0 +1,98 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde_json::json;
+
+use re9k::analysis::{self, FunctionFinding, Sample};
+
+const SAMPLES: [&str; 4] = ["traceme", "sigtrap", "dontdump", "flatcfg"];
+
+fn root() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn have_r2() -> bool {
+    Command::new("r2")
+        .arg("-v")
+        .output()
+        .map(|out| out.status.success())
+        .unwrap_or(false)
+}
+
+fn build(arch: &str) -> bool {
+    Command::new("make")
+        .arg("-s")
+        .arg("-C")
+        .arg(root().join("tests/corpus"))
+        .arg(arch)
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+// Only the detection results are pinned, the rest of the report depends on
+// the r2 version and the toolchain.
+fn golden_report(sample: &Sample) -> serde_json::Value {
+    let names = |findings: &[FunctionFinding]| {
+        findings.iter().map(|f| f.name.clone()).collect::<Vec<_>>()
+    };
+    json!({
+        "functions": names(&sample.functions),
+        "params": sample.params,
+        "cff": names(&sample.cff),
+    })
+}
+
+// The tests are #[ignore]d so a plain `cargo test` doesn't pass without
+// having run them. Once asked for, a missing r2 or toolchain is a failure.
+fn check_arch(arch: &str) {
+    assert!(have_r2(), "radare2 not found, the corpus tests need r2 on PATH");
+    assert!(build(arch), "couldn't build the {arch} corpus, is its cross-compiler installed?");
+
+    let bless = std::env::var_os("RE9K_BLESS").is_some();
+    let mut failures = vec![];
+
+    for name in SAMPLES {
+        let bin: PathBuf = root().join("tests/corpus/bin").join(arch).join(name);
+        let golden = root().join("tests/golden").join(format!("{name}.{arch}.json"));
+
+        let sample = analysis::inspect(bin.to_str().unwrap()).expect("inspect corpus binary");
+        let report = golden_report(&sample);
+
+        if bless {
+            std::fs::create_dir_all(golden.parent().unwrap()).expect("create tests/golden");
+            let file = File::create(&golden).expect("create golden report");
+            serde_json::to_writer_pretty(file, &report).expect("write golden report");
+            continue;
+        }
+
+        let file = File::open(&golden).unwrap_or_else(|_| {
+            panic!("no {}, generate it with RE9K_BLESS=1", golden.display())
+        });
+        let expected: serde_json::Value = serde_json::from_reader(file).expect("parse golden report");
+        if report != expected {
+            failures.push(format!("{name}.{arch}:\n  expected {expected}\n  got      {report}"));
+        }
+    }
+
+    assert!(failures.is_empty(), "golden mismatch:\n{}", failures.join("\n"));
+}
+
+#[test]
+#[ignore = "needs radare2, run with --include-ignored"]
+fn corpus_x86_64() {
+    check_arch("x86_64");
+}
+
+#[test]
+#[ignore = "needs radare2 and gcc-aarch64-linux-gnu, run with --include-ignored"]
+fn corpus_aarch64() {
+    check_arch("aarch64");
+}
+
+#[test]
+#[ignore = "needs radare2 and gcc-arm-linux-gnueabihf, run with --include-ignored"]
+fn corpus_arm() {
+    check_arch("arm");
+}
@@ -0,0 +1,15 @@
+#include <stdio.h>
+#include <string.h>
+#include <sys/mman.h>
+
+int main(void)
+{
+    char *secret = mmap(NULL, 4096, PROT_READ | PROT_WRITE,
+                        MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    if (secret == MAP_FAILED)
+        return 1;
+    madvise(secret, 4096, MADV_DONTDUMP);
+    strcpy(secret, "hunter2");
+    puts("ok");
+    return 0;
+}
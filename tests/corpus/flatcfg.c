@@ -0,0 +1,37 @@
+#include <stdio.h>
+
+/* Hand-flattened control flow: every block returns to a single dispatcher. */
+int main(int argc, char **argv)
+{
+    (void)argv;
+    int state = 0;
+    int acc = argc;
+
+    while (1) {
+        switch (state) {
+        case 0:
+            acc += 3;
+            state = 4;
+            break;
+        case 1:
+            acc *= 7;
+            state = 3;
+            break;
+        case 2:
+            acc -= 11;
+            state = 5;
+            break;
+        case 3:
+            acc ^= 0x55;
+            state = 2;
+            break;
+        case 4:
+            acc <<= 1;
+            state = acc & 1 ? 2 : 1;
+            break;
+        case 5:
+            printf("%d\n", acc);
+            return 0;
+        }
+    }
+}
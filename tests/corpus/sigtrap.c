@@ -0,0 +1,22 @@
+#include <signal.h>
+#include <stdio.h>
+
+static volatile int trapped;
+
+static void handler(int sig)
+{
+    (void)sig;
+    trapped = 1;
+}
+
+int main(void)
+{
+    signal(SIGTRAP, handler);
+    raise(SIGTRAP);
+    if (!trapped) {
+        puts("debugger detected");
+        return 1;
+    }
+    puts("ok");
+    return 0;
+}
@@ -0,0 +1,12 @@
+#include <stdio.h>
+#include <sys/ptrace.h>
+
+int main(void)
+{
+    if (ptrace(PTRACE_TRACEME, 0, 0, 0) == -1) {
+        puts("debugger detected");
+        return 1;
+    }
+    puts("ok");
+    return 0;
+}